@@ -1,16 +1,37 @@
+use parking_lot::{MappedMutexGuard, MutexGuard};
 use rand::rngs::OsRng;
+use rusqlite::Connection;
 use serde::Serialize;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use tauri::Emitter;
 use tauri_plugin_dialog::DialogExt;
-use tracing::error;
-use zeroize::Zeroize;
+use tracing::{error, info};
+use zeroize::Zeroizing;
 
 use crate::{
-    database::open_database,
+    crypto::{
+        compression::Compression, keyfile::digest_keyfile, padding::Padding,
+        servicekeys::ServiceKeys,
+    },
+    database::{
+        RecordRepository, TopicRepository, check_integrity, ensure_writable, is_read_only,
+        migrations::pending_upgrade, open_database, records,
+    },
+    error::IterateError,
     services::{
         databasecleaner::purge_old_deleted_records,
-        gatekeeper::{initialize_key_store, verify_password},
+        gatekeeper::{
+            self, KeySlotInfo, add_key_slot, add_recovery_key, initialize_key_store,
+            list_key_slots, remove_key_slot, revoke_recovery_keys, verify_password,
+            verify_recovery_phrase,
+        },
+        journalchain::{self, ChainBreak},
+        journallock::{JournalLock, LockHolder},
+        keyrotation::{RotationProgress, is_rotation_pending, rotate_master_key},
+        privacymode, recordcipher,
     },
-    state::AppState,
+    state::{AppConfig, AppState, Session},
 };
 
 #[derive(Debug, Serialize)]
@@ -19,17 +40,228 @@ pub enum JournalOpeningError {
     Cancelled,
     InternalError(String),
     InvalidPassword,
+    InvalidRecoveryPhrase,
     InvalidState,
+    JournalExists,
+    InvalidLabel,
+    LastKeySlot,
+    KeyfileRequired,
+    KeyfileMismatch,
+    KeyfileNotFound,
+    InsufficientMemory,
+    JournalChainBroken,
+    UnsupportedSchemaVersion,
+    MigrationFailed,
+    ReadOnlyJournal,
+    JournalInUse(LockHolder),
+    RotationPending,
+    AlreadyUnlocked,
+}
+
+/// Shown once after a key rotation finished: the new recovery phrase and the unlock
+/// slots that were revoked, because only the slot used for the rotation is re-wrapped.
+#[derive(Debug, Serialize)]
+pub struct RotationSummary {
+    pub recovery_phrase: String,
+    pub revoked_slots: Vec<KeySlotInfo>,
+}
+
+/// Emitted with a `RotationProgress` payload while records are re-encrypted.
+pub const KEY_ROTATION_PROGRESS_EVENT: &str = "key-rotation-progress";
+
+/// Emitted with a `ChainBreak` payload when the record chain check at unlock fails.
+pub const JOURNAL_CHAIN_BROKEN_EVENT: &str = "journal-chain-broken";
+
+/// Emitted with a `SchemaUpgrade` payload before an opened journal is upgraded,
+/// so that the user knows why opening takes longer and that a backup is made.
+pub const JOURNAL_SCHEMA_UPGRADE_EVENT: &str = "journal-schema-upgrade";
+
+/// Maintenance that needs the service keys, run right after a successful unlock.
+/// The chain is checked first, so neither the upgrade of older rows nor the purge can
+/// bless or mask earlier tampering, and a broken chain skips the upgrade.
+/// A read-only journal only gets the chain check.
+fn run_unlocked_maintenance(
+    app: &tauri::AppHandle,
+    conn: &Connection,
+    keys: &ServiceKeys,
+    config: &AppConfig,
+) {
+    let read_only = is_read_only(conn).unwrap_or(true);
+
+    // An unfinished rotation leaves the chain half re-keyed until it completes.
+    let mut chain_intact = true;
+    if config.verify_chain_on_open && !is_rotation_pending(conn).unwrap_or(true) {
+        match journalchain::verify_journal_chain(conn, keys) {
+            Ok(None) => {}
+            Ok(Some(chain_break)) => {
+                chain_intact = false;
+                if let Err(e) = app.emit(JOURNAL_CHAIN_BROKEN_EVENT, chain_break) {
+                    error!("Emitting chain break failed: {}", e);
+                }
+            }
+            Err(e) => {
+                chain_intact = false;
+                error!("Verifying the record chain failed: {}", e);
+            }
+        }
+    }
+
+    if read_only {
+        return;
+    }
+    if chain_intact {
+        authenticate_legacy_rows(conn, keys);
+    }
+    encrypt_legacy_topics(conn, keys);
+    if let Err(e) = purge_old_deleted_records(conn, keys, config.soft_delete_retention_days) {
+        error!("DB purge failed: {}", e);
+    }
+}
+
+fn authenticate_legacy_rows(conn: &Connection, keys: &ServiceKeys) {
+    match RecordRepository::new(conn, keys).authenticate_legacy_rows() {
+        Ok(0) => {}
+        Ok(count) => info!("Authenticated the metadata of {} existing records", count),
+        Err(e) => error!("Authenticating existing records failed: {}", e),
+    }
+}
+
+fn encrypt_legacy_topics(conn: &Connection, keys: &ServiceKeys) {
+    match TopicRepository::new(conn, keys).encrypt_legacy_rows(&mut OsRng) {
+        Ok(0) => {}
+        Ok(count) => info!("Encrypted {} existing topics and prompts", count),
+        Err(e) => error!("Encrypting existing topics failed: {}", e),
+    }
+}
+
+/// The connection of the unlocked session, or a fresh one while the journal is only opened.
+/// A fresh connection keeps the session, so that the journal and its lock stay selected
+/// until it is closed.
+enum JournalConnection<'a> {
+    Session(MappedMutexGuard<'a, Connection>),
+    Opened(Connection, MutexGuard<'a, Session>),
+}
+
+impl Deref for JournalConnection<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        match self {
+            JournalConnection::Session(conn) => conn,
+            JournalConnection::Opened(conn, _) => conn,
+        }
+    }
+}
+
+impl DerefMut for JournalConnection<'_> {
+    fn deref_mut(&mut self) -> &mut Connection {
+        match self {
+            JournalConnection::Session(conn) => conn,
+            JournalConnection::Opened(conn, _) => conn,
+        }
+    }
+}
+
+/// For commands that work on a locked journal as well. While it is unlocked they reuse
+/// the session connection and hold the session for their duration.
+fn journal_connection(state: &AppState) -> Result<JournalConnection<'_>, JournalOpeningError> {
+    let session = state.session.lock();
+    let session = match MutexGuard::try_map(session, |session| {
+        session.unlocked_mut().map(|(conn, _)| conn)
+    }) {
+        Ok(conn) => return Ok(JournalConnection::Session(conn)),
+        Err(session) => session,
+    };
+    let db_path = session
+        .path()
+        .cloned()
+        .ok_or(JournalOpeningError::InvalidState)?;
+
+    state.hold_journal_lock(&session).map_err(lock_error)?;
+    let conn = open_database(db_path).map_err(|e| {
+        error!("open_database failed: {}", e);
+        JournalOpeningError::InternalError("The database didn't open.".to_string())
+    })?;
+    Ok(JournalConnection::Opened(conn, session))
+}
+
+/// The opened journal, once this instance made sure it still holds its lock.
+fn journal_to_unlock(state: &AppState) -> Result<PathBuf, JournalOpeningError> {
+    let session = state.session.lock();
+    let db_path = match &*session {
+        Session::Opened { path } => path.clone(),
+        Session::Unlocked { .. } => return Err(JournalOpeningError::AlreadyUnlocked),
+        Session::NoJournal | Session::Locking { .. } => {
+            return Err(JournalOpeningError::InvalidState);
+        }
+    };
+
+    state.hold_journal_lock(&session).map_err(lock_error)?;
+    Ok(db_path)
+}
+
+/// Takes the single-writer lock of the journal, or names the instance holding it.
+fn acquire_lock(path: &Path) -> Result<JournalLock, JournalOpeningError> {
+    JournalLock::acquire(path).map_err(lock_error)
+}
+
+fn lock_error(e: IterateError) -> JournalOpeningError {
+    match e {
+        IterateError::JournalInUse(holder) => JournalOpeningError::JournalInUse(holder),
+        e => {
+            error!("Acquiring the journal lock failed: {}", e);
+            JournalOpeningError::InternalError("The journal could not be locked.".to_string())
+        }
+    }
+}
+
+/// Hashes the keyfile at `path`, if the user chose one.
+fn read_keyfile(path: Option<PathBuf>) -> Result<Option<Zeroizing<[u8; 64]>>, JournalOpeningError> {
+    path.map(|path| digest_keyfile(&path))
+        .transpose()
+        .map_err(|e| match e {
+            IterateError::KeyfileNotFound => JournalOpeningError::KeyfileNotFound,
+            e => {
+                error!("digest_keyfile failed: {}", e);
+                JournalOpeningError::InternalError("The keyfile could not be read.".to_string())
+            }
+        })
+}
+
+/// Maps the errors of an unlock attempt, so wrong passwords and keyfile problems stay distinguishable.
+fn unlock_error(e: IterateError) -> JournalOpeningError {
+    match e {
+        IterateError::KeyfileRequired => JournalOpeningError::KeyfileRequired,
+        IterateError::KeyfileMismatch => JournalOpeningError::KeyfileMismatch,
+        IterateError::InsufficientKdfMemory => JournalOpeningError::InsufficientMemory,
+        e => {
+            error!("{}", e);
+            JournalOpeningError::InvalidPassword
+        }
+    }
+}
+
+/// Rejects commands that write to a journal opened read-only.
+fn writable(conn: &Connection) -> Result<(), JournalOpeningError> {
+    ensure_writable(conn).map_err(|e| match e {
+        IterateError::ReadOnlyJournal => JournalOpeningError::ReadOnlyJournal,
+        e => {
+            error!("Checking the journal access failed: {}", e);
+            JournalOpeningError::InternalError("The journal could not be read.".to_string())
+        }
+    })
 }
 
 #[tauri::command]
 pub async fn create_journal(
     app: tauri::AppHandle,
     password: Vec<u8>,
+    keyfile_path: Option<PathBuf>,
     state: tauri::State<'_, AppState>,
-) -> Result<(), JournalOpeningError> {
-    let mut password = password;
+) -> Result<String, JournalOpeningError> {
+    let password = Zeroizing::new(password);
     tracing::debug!("{}", password.len());
+    let keyfile = read_keyfile(keyfile_path)?;
     let path = app
         .dialog()
         .file()
@@ -40,45 +272,57 @@ pub async fn create_journal(
         .map_err(|_| JournalOpeningError::InternalError("Invalid Path".to_string()))?;
     tracing::debug!("Path");
 
+    // Creating a journal ends the current session, which also releases its lock.
+    state.close_journal();
+    let lock = acquire_lock(&path)?;
     let mut conn = open_database(path.clone()).map_err(|e| {
         error!("open_database failed: {}", e);
         return JournalOpeningError::InternalError("The database didn't open.".to_string());
     })?;
 
-    if let Err(e) = initialize_key_store(&mut OsRng, &mut conn, &password) {
-        match e {
-            crate::error::IterateError::SerializationFailed(error) => {
-                return Err(JournalOpeningError::InternalError(error.to_string()));
-            }
-            crate::error::IterateError::SystemRngFailure => {
-                return Err(JournalOpeningError::InternalError(
-                    "Catastrophic system error".to_string(),
-                ));
-            }
-            crate::error::IterateError::KeyDerivationFailed => {
-                return Err(JournalOpeningError::InternalError(
-                    "Crypto error".to_string(),
-                ));
-            }
-            crate::error::IterateError::HkdfExpansionFailed => {
-                return Err(JournalOpeningError::InternalError(
-                    "Crypto error".to_string(),
-                ));
-            }
-            crate::error::IterateError::Database(error) => {
-                return Err(JournalOpeningError::InternalError(error.to_string()));
-            }
-            _ => todo!(),
+    let created = initialize_key_store(
+        &mut OsRng,
+        &mut conn,
+        &password,
+        keyfile.as_ref().map(|k| k.as_slice()),
+    );
+
+    let (_, recovery_phrase) = created.map_err(|e| match e {
+        IterateError::KeyStoreExists => JournalOpeningError::JournalExists,
+        IterateError::InsufficientKdfMemory => JournalOpeningError::InsufficientMemory,
+        IterateError::SystemRngFailure => {
+            JournalOpeningError::InternalError("Catastrophic system error".to_string())
         }
-    }
+        IterateError::KeyDerivationFailed
+        | IterateError::HkdfExpansionFailed
+        | IterateError::AeadIntegrityFailure => {
+            JournalOpeningError::InternalError("Crypto error".to_string())
+        }
+        IterateError::Database(error) => JournalOpeningError::InternalError(error.to_string()),
+        IterateError::SerializationFailed(error) => {
+            JournalOpeningError::InternalError(error.to_string())
+        }
+        IterateError::PostCardSerializationFailed(error) => {
+            JournalOpeningError::InternalError(error)
+        }
+        e => {
+            error!("initialize_key_store failed: {}", e);
+            JournalOpeningError::InternalError("The journal was not created.".to_string())
+        }
+    })?;
 
-    password.zeroize();
-    let mut db_path_handle = state.db_path.lock();
-    *db_path_handle = Some(path);
+    state.open_journal(path, lock).map_err(|e| {
+        error!("{}", e);
+        JournalOpeningError::InvalidState
+    })?;
 
-    Ok(())
+    Ok(recovery_phrase.to_string())
 }
 
+/// Selects the journal file. The record chain needs the service keys, so the check
+/// enabled by `AppConfig::verify_chain_on_open` runs once the journal is unlocked.
+/// The journal lock is held until another journal is opened. An upgrade is announced through
+/// `JOURNAL_SCHEMA_UPGRADE_EVENT` before it starts.
 #[tauri::command]
 pub async fn open_journal(
     app: tauri::AppHandle,
@@ -97,51 +341,531 @@ pub async fn open_journal(
         None => return Err(JournalOpeningError::Cancelled), // Explicitly tell frontend it was cancelled
     };
 
-    let conn = open_database(path.clone()).map_err(|e| {
-        error!("open_database failed: {}", e);
-        return JournalOpeningError::InternalError("The database didn't open.".to_string());
+    // Opening a journal ends the current session, which also releases its lock.
+    state.close_journal();
+    let lock = acquire_lock(&path)?;
+    match pending_upgrade(&path) {
+        Ok(Some(upgrade)) => {
+            if let Err(e) = app.emit(JOURNAL_SCHEMA_UPGRADE_EVENT, upgrade) {
+                error!("Emitting schema upgrade failed: {}", e);
+            }
+        }
+        Ok(None) => {}
+        Err(e) => error!("Reading the journal schema version failed: {}", e),
+    }
+    let conn = open_database(path.clone()).map_err(|e| match e {
+        IterateError::UnsupportedSchemaVersion { .. } => {
+            JournalOpeningError::UnsupportedSchemaVersion
+        }
+        IterateError::MigrationFailed(_) => {
+            error!("open_database failed: {}", e);
+            JournalOpeningError::MigrationFailed
+        }
+        e => {
+            error!("open_database failed: {}", e);
+            JournalOpeningError::InternalError("The database didn't open.".to_string())
+        }
     })?;
-    conn.execute("PRAGMA integrity_check;", [])
-        .map_err(|_| JournalOpeningError::InternalError("DB Integrity failure".to_string()))?;
-
-    let mut db_path_handle = state.db_path.lock();
-    *db_path_handle = Some(path);
+    check_integrity(&conn).map_err(|e| {
+        error!("check_integrity failed: {}", e);
+        JournalOpeningError::InternalError("DB Integrity failure".to_string())
+    })?;
+    drop(conn);
 
-    let app_conf = state.app_config.lock();
-    if let Err(e) = purge_old_deleted_records(&conn, app_conf.soft_delete_retention_days) {
-        error!("DB purge failed: {}", e);
-    }
+    state.open_journal(path, lock).map_err(|e| {
+        error!("{}", e);
+        JournalOpeningError::InvalidState
+    })?;
 
     Ok(())
 }
 
+/// Unlocks the journal. If a content key rotation was interrupted it is finished first,
+/// and its new recovery phrase and revoked slots are returned.
 #[tauri::command]
 pub async fn unlock_journal(
+    app: tauri::AppHandle,
     password: Vec<u8>,
+    keyfile_path: Option<PathBuf>,
     state: tauri::State<'_, AppState>,
-) -> Result<(), JournalOpeningError> {
-    let mut password = password;
-    let db_path = {
-        let guard = state.db_path.lock();
-        guard
-            .as_ref()
-            .cloned()
-            .ok_or(JournalOpeningError::InvalidState)?
+) -> Result<Option<RotationSummary>, JournalOpeningError> {
+    let password = Zeroizing::new(password);
+    let keyfile = read_keyfile(keyfile_path)?;
+    let db_path = journal_to_unlock(&state)?;
+    let mut conn = open_database(db_path.clone()).map_err(|e| {
+        error!("open_database failed: {}", e);
+        return JournalOpeningError::InternalError("The database didn't open.".to_string());
+    })?;
+
+    let service_keys = verify_password(
+        &mut OsRng,
+        &mut conn,
+        &password,
+        keyfile.as_ref().map(|k| k.as_slice()),
+    )
+    .map_err(unlock_error)?;
+
+    let mut rotation_summary = None;
+    let rotation_pending = is_rotation_pending(&conn).map_err(|e| {
+        error!("is_rotation_pending failed: {}", e);
+        JournalOpeningError::InternalError("The journal could not be read.".to_string())
+    })?;
+    // Finishing the rotation is a write, which is up to the version that wrote the journal.
+    if rotation_pending {
+        writable(&conn)?;
+    }
+    let service_keys = if rotation_pending {
+        let outcome = rotate_master_key(
+            &mut OsRng,
+            &mut conn,
+            &password,
+            keyfile.as_ref().map(|k| k.as_slice()),
+            |progress| emit_rotation_progress(&app, &state, progress),
+        )
+        .map_err(|e| {
+            error!("Resuming key rotation failed: {}", e);
+            JournalOpeningError::InternalError(
+                "The key rotation could not be finished.".to_string(),
+            )
+        })?;
+        rotation_summary = Some(RotationSummary {
+            recovery_phrase: outcome.recovery_phrase.to_string(),
+            revoked_slots: outcome.revoked_slots,
+        });
+        outcome.service_keys
+    } else {
+        service_keys
     };
 
-    let mut conn = open_database(db_path).map_err(|e| {
+    let app_config = state.app_config.lock().clone();
+    run_unlocked_maintenance(&app, &conn, &service_keys, &app_config);
+
+    state.record_activity();
+    state
+        .unlock_journal(&db_path, service_keys, conn)
+        .map_err(|e| {
+            error!("{}", e);
+            JournalOpeningError::InvalidState
+        })?;
+
+    Ok(rotation_summary)
+}
+
+/// Unlocks the journal with a recovery phrase. An interrupted key rotation is not
+/// finished here and fails with `RotationPending`; it needs the password.
+#[tauri::command]
+pub async fn unlock_journal_with_recovery_phrase(
+    app: tauri::AppHandle,
+    recovery_phrase: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), JournalOpeningError> {
+    let recovery_phrase = Zeroizing::new(recovery_phrase);
+    let db_path = journal_to_unlock(&state)?;
+    let conn = open_database(db_path.clone()).map_err(|e| {
         error!("open_database failed: {}", e);
-        return JournalOpeningError::InternalError("The database didn't open.".to_string());
+        JournalOpeningError::InternalError("The database didn't open.".to_string())
     })?;
 
-    let service_keys = verify_password(&mut conn, &password).map_err(|e| {
+    let result = verify_recovery_phrase(&conn, &recovery_phrase);
+    let service_keys = result.map_err(|e| {
         error!("{}", e);
-        return JournalOpeningError::InvalidPassword;
+        JournalOpeningError::InvalidRecoveryPhrase
+    })?;
+    // Finishing the rotation re-wraps the slot it runs with and revokes the others,
+    // which would leave the journal without a password.
+    let rotation_pending = is_rotation_pending(&conn).map_err(|e| {
+        error!("is_rotation_pending failed: {}", e);
+        JournalOpeningError::InternalError("The journal could not be read.".to_string())
     })?;
+    if rotation_pending {
+        return Err(JournalOpeningError::RotationPending);
+    }
 
-    let mut master_key_handle = state.serivce_keys.lock();
-    *master_key_handle = Some(service_keys);
-    password.zeroize();
+    let app_config = state.app_config.lock().clone();
+    run_unlocked_maintenance(&app, &conn, &service_keys, &app_config);
+
+    state.record_activity();
+    state
+        .unlock_journal(&db_path, service_keys, conn)
+        .map_err(|e| {
+            error!("{}", e);
+            JournalOpeningError::InvalidState
+        })?;
 
     Ok(())
 }
+
+#[tauri::command]
+pub async fn change_password(
+    old_password: Vec<u8>,
+    new_password: Vec<u8>,
+    keyfile_path: Option<PathBuf>,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), JournalOpeningError> {
+    let old_password = Zeroizing::new(old_password);
+    let new_password = Zeroizing::new(new_password);
+    let keyfile = read_keyfile(keyfile_path)?;
+    let mut conn = journal_connection(&state)?;
+    writable(&conn)?;
+
+    let result = gatekeeper::change_password(
+        &mut OsRng,
+        &mut conn,
+        &old_password,
+        &new_password,
+        keyfile.as_ref().map(|k| k.as_slice()),
+    );
+
+    result.map_err(|e| match e {
+        IterateError::InvalidPassword => JournalOpeningError::InvalidPassword,
+        IterateError::KeyfileRequired => JournalOpeningError::KeyfileRequired,
+        IterateError::KeyfileMismatch => JournalOpeningError::KeyfileMismatch,
+        IterateError::InsufficientKdfMemory => JournalOpeningError::InsufficientMemory,
+        e => {
+            error!("change_password failed: {}", e);
+            JournalOpeningError::InternalError("The password was not changed.".to_string())
+        }
+    })
+}
+
+/// Creates an additional recovery key and returns its phrase.
+#[tauri::command]
+pub async fn add_recovery_phrase(
+    password: Vec<u8>,
+    keyfile_path: Option<PathBuf>,
+    state: tauri::State<'_, AppState>,
+) -> Result<String, JournalOpeningError> {
+    let password = Zeroizing::new(password);
+    let keyfile = read_keyfile(keyfile_path)?;
+    let conn = journal_connection(&state)?;
+    writable(&conn)?;
+
+    let result = add_recovery_key(
+        &mut OsRng,
+        &conn,
+        &password,
+        keyfile.as_ref().map(|k| k.as_slice()),
+    );
+
+    let recovery_phrase = result.map_err(|e| match e {
+        IterateError::InvalidPassword => JournalOpeningError::InvalidPassword,
+        IterateError::KeyfileRequired => JournalOpeningError::KeyfileRequired,
+        IterateError::KeyfileMismatch => JournalOpeningError::KeyfileMismatch,
+        IterateError::InsufficientKdfMemory => JournalOpeningError::InsufficientMemory,
+        e => {
+            error!("add_recovery_key failed: {}", e);
+            JournalOpeningError::InternalError("The recovery key was not created.".to_string())
+        }
+    })?;
+
+    Ok(recovery_phrase.to_string())
+}
+
+/// Invalidates every recovery phrase of the unlocked journal.
+#[tauri::command]
+pub async fn revoke_recovery_phrases(
+    state: tauri::State<'_, AppState>,
+) -> Result<usize, JournalOpeningError> {
+    let mut session = state.session.lock();
+    let (conn, _) = session
+        .unlocked_mut()
+        .ok_or(JournalOpeningError::InvalidState)?;
+    writable(conn)?;
+
+    revoke_recovery_keys(conn).map_err(|e| {
+        error!("revoke_recovery_keys failed: {}", e);
+        JournalOpeningError::InternalError("The recovery keys were not revoked.".to_string())
+    })
+}
+
+#[tauri::command]
+pub async fn list_unlock_slots(
+    state: tauri::State<'_, AppState>,
+) -> Result<Vec<KeySlotInfo>, JournalOpeningError> {
+    let session = state.session.lock();
+    let (conn, _) = session
+        .unlocked()
+        .ok_or(JournalOpeningError::InvalidState)?;
+
+    list_key_slots(conn).map_err(|e| {
+        error!("list_key_slots failed: {}", e);
+        JournalOpeningError::InternalError("The unlock slots could not be read.".to_string())
+    })
+}
+
+/// Adds a labelled unlock secret, e.g. a second password or the bytes of a keyfile.
+/// With `new_keyfile_path` the new slot requires that keyfile next to `secret`.
+#[tauri::command]
+pub async fn add_unlock_slot(
+    current_password: Vec<u8>,
+    current_keyfile_path: Option<PathBuf>,
+    label: String,
+    secret: Vec<u8>,
+    new_keyfile_path: Option<PathBuf>,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), JournalOpeningError> {
+    let current_password = Zeroizing::new(current_password);
+    let secret = Zeroizing::new(secret);
+    let current_keyfile = read_keyfile(current_keyfile_path)?;
+    let new_keyfile = read_keyfile(new_keyfile_path)?;
+    let label = label.trim();
+    if label.is_empty() {
+        return Err(JournalOpeningError::InvalidLabel);
+    }
+    let conn = journal_connection(&state)?;
+    writable(&conn)?;
+
+    let result = add_key_slot(
+        &mut OsRng,
+        &conn,
+        &current_password,
+        current_keyfile.as_ref().map(|k| k.as_slice()),
+        label,
+        &secret,
+        new_keyfile.as_ref().map(|k| k.as_slice()),
+    );
+
+    result.map_err(|e| match e {
+        IterateError::InvalidPassword => JournalOpeningError::InvalidPassword,
+        IterateError::KeyfileRequired => JournalOpeningError::KeyfileRequired,
+        IterateError::KeyfileMismatch => JournalOpeningError::KeyfileMismatch,
+        IterateError::InsufficientKdfMemory => JournalOpeningError::InsufficientMemory,
+        e => {
+            error!("add_key_slot failed: {}", e);
+            JournalOpeningError::InternalError("The unlock slot was not added.".to_string())
+        }
+    })
+}
+
+#[tauri::command]
+pub async fn remove_unlock_slot(
+    slot_id: i64,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), JournalOpeningError> {
+    let mut session = state.session.lock();
+    let (conn, _) = session
+        .unlocked_mut()
+        .ok_or(JournalOpeningError::InvalidState)?;
+    writable(conn)?;
+
+    remove_key_slot(conn, slot_id).map_err(|e| match e {
+        IterateError::LastKeySlot => JournalOpeningError::LastKeySlot,
+        e => {
+            error!("remove_key_slot failed: {}", e);
+            JournalOpeningError::InternalError("The unlock slot was not removed.".to_string())
+        }
+    })
+}
+
+/// Generates a new master key and re-encrypts every record with it.
+/// Progress is reported through `KEY_ROTATION_PROGRESS_EVENT`. Other unlock slots are
+/// revoked and replaced by a new recovery key; both are returned.
+#[tauri::command]
+pub async fn rotate_content_key(
+    app: tauri::AppHandle,
+    password: Vec<u8>,
+    keyfile_path: Option<PathBuf>,
+    state: tauri::State<'_, AppState>,
+) -> Result<RotationSummary, JournalOpeningError> {
+    let password = Zeroizing::new(password);
+    let keyfile = read_keyfile(keyfile_path)?;
+    let mut session = state.session.lock();
+    let (conn, keys) = session
+        .unlocked_mut()
+        .ok_or(JournalOpeningError::InvalidState)?;
+    writable(conn)?;
+
+    let result = rotate_master_key(
+        &mut OsRng,
+        conn,
+        &password,
+        keyfile.as_ref().map(|k| k.as_slice()),
+        |progress| emit_rotation_progress(&app, &state, progress),
+    );
+
+    let outcome = result.map_err(|e| match e {
+        IterateError::InvalidPassword
+        | IterateError::KeyfileRequired
+        | IterateError::KeyfileMismatch
+        | IterateError::InsufficientKdfMemory => unlock_error(e),
+        IterateError::JournalChainBroken => JournalOpeningError::JournalChainBroken,
+        e => {
+            error!("rotate_master_key failed: {}", e);
+            JournalOpeningError::InternalError("The key rotation did not finish.".to_string())
+        }
+    })?;
+
+    *keys = outcome.service_keys;
+
+    Ok(RotationSummary {
+        recovery_phrase: outcome.recovery_phrase.to_string(),
+        revoked_slots: outcome.revoked_slots,
+    })
+}
+
+/// Walks the record chain of the unlocked journal and returns the first break, if any.
+#[tauri::command]
+pub async fn verify_journal_chain(
+    state: tauri::State<'_, AppState>,
+) -> Result<Option<ChainBreak>, JournalOpeningError> {
+    let session = state.session.lock();
+    let (conn, keys) = session
+        .unlocked()
+        .ok_or(JournalOpeningError::InvalidState)?;
+
+    journalchain::verify_journal_chain(conn, keys).map_err(|e| {
+        error!("verify_journal_chain failed: {}", e);
+        JournalOpeningError::InternalError("The journal could not be verified.".to_string())
+    })
+}
+
+/// Returns the padding bucket policy applied to newly written entries.
+#[tauri::command]
+pub async fn get_padding_policy(
+    state: tauri::State<'_, AppState>,
+) -> Result<Padding, JournalOpeningError> {
+    let conn = journal_connection(&state)?;
+
+    recordcipher::padding_policy(&conn).map_err(|e| {
+        error!("padding_policy failed: {}", e);
+        JournalOpeningError::InternalError("The journal settings could not be read.".to_string())
+    })
+}
+
+/// Changes the padding bucket policy. Entries keep their padding until they are saved again.
+#[tauri::command]
+pub async fn set_padding_policy(
+    padding: Padding,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), JournalOpeningError> {
+    let session = state.session.lock();
+    let (conn, _) = session
+        .unlocked()
+        .ok_or(JournalOpeningError::InvalidState)?;
+    writable(conn)?;
+
+    recordcipher::set_padding_policy(conn, padding).map_err(|e| {
+        error!("set_padding_policy failed: {}", e);
+        JournalOpeningError::InternalError("The journal settings were not saved.".to_string())
+    })
+}
+
+/// Returns the compression applied to newly written entries.
+#[tauri::command]
+pub async fn get_compression_policy(
+    state: tauri::State<'_, AppState>,
+) -> Result<Compression, JournalOpeningError> {
+    let conn = journal_connection(&state)?;
+
+    recordcipher::compression_policy(&conn).map_err(|e| {
+        error!("compression_policy failed: {}", e);
+        JournalOpeningError::InternalError("The journal settings could not be read.".to_string())
+    })
+}
+
+/// Turns compression of newly written entries on or off.
+#[tauri::command]
+pub async fn set_compression_policy(
+    compression: Compression,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), JournalOpeningError> {
+    let session = state.session.lock();
+    let (conn, _) = session
+        .unlocked()
+        .ok_or(JournalOpeningError::InvalidState)?;
+    writable(conn)?;
+
+    recordcipher::set_compression_policy(conn, compression).map_err(|e| {
+        error!("set_compression_policy failed: {}", e);
+        JournalOpeningError::InternalError("The journal settings were not saved.".to_string())
+    })
+}
+
+/// Returns true if records keep their timestamps, sentiment and flags encrypted.
+#[tauri::command]
+pub async fn get_privacy_mode(
+    state: tauri::State<'_, AppState>,
+) -> Result<bool, JournalOpeningError> {
+    let conn = journal_connection(&state)?;
+
+    records::privacy_mode(&conn).map_err(|e| {
+        error!("privacy_mode failed: {}", e);
+        JournalOpeningError::InternalError("The journal settings could not be read.".to_string())
+    })
+}
+
+/// Turns privacy mode on or off. Unlike the other settings this rewrites every record
+/// right away, so no exact timestamps are left behind in plaintext. Turning it on also
+/// replaces the time-ordered ids of existing records with random ones and restarts the
+/// record chain, which named them in order.
+#[tauri::command]
+pub async fn set_privacy_mode(
+    enabled: bool,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), JournalOpeningError> {
+    let session = state.session.lock();
+    let (conn, keys) = session
+        .unlocked()
+        .ok_or(JournalOpeningError::InvalidState)?;
+    writable(conn)?;
+
+    let rewritten =
+        privacymode::set_privacy_mode(&mut OsRng, conn, keys, enabled).map_err(|e| match e {
+            IterateError::JournalChainBroken => JournalOpeningError::JournalChainBroken,
+            e => {
+                error!("set_privacy_mode failed: {}", e);
+                JournalOpeningError::InternalError(
+                    "The journal settings were not saved.".to_string(),
+                )
+            }
+        })?;
+    info!("Privacy mode set to {} for {} records", enabled, rewritten);
+    state.record_activity();
+
+    Ok(())
+}
+
+/// Runs the full integrity check of the database file on demand.
+/// Opening a journal runs it once; saves and other commands skip it.
+#[tauri::command]
+pub async fn check_journal_integrity(
+    state: tauri::State<'_, AppState>,
+) -> Result<bool, JournalOpeningError> {
+    let conn = journal_connection(&state)?;
+    let result = check_integrity(&conn);
+    // The check reads the whole file, which can take longer than the idle timeout.
+    state.record_activity();
+
+    match result {
+        Ok(()) => Ok(true),
+        Err(IterateError::DatabaseIntegrity) => Ok(false),
+        Err(e) => {
+            error!("check_integrity failed: {}", e);
+            Err(JournalOpeningError::InternalError(
+                "The integrity check did not run.".to_string(),
+            ))
+        }
+    }
+}
+
+/// Returns true if the journal was written by a newer version and can only be read.
+#[tauri::command]
+pub async fn is_journal_read_only(
+    state: tauri::State<'_, AppState>,
+) -> Result<bool, JournalOpeningError> {
+    let conn = journal_connection(&state)?;
+
+    is_read_only(&conn).map_err(|e| {
+        error!("is_read_only failed: {}", e);
+        JournalOpeningError::InternalError("The journal could not be read.".to_string())
+    })
+}
+
+/// Each batch also counts as activity, so the idle lock does not fire as soon as a long
+/// rotation is done.
+fn emit_rotation_progress(app: &tauri::AppHandle, state: &AppState, progress: RotationProgress) {
+    state.record_activity();
+    if let Err(e) = app.emit(KEY_ROTATION_PROGRESS_EVENT, progress) {
+        error!("Emitting rotation progress failed: {}", e);
+    }
+}
@@ -1,2 +1,4 @@
 pub mod journal;
 pub mod record;
+pub mod session;
+pub mod topic;
@@ -2,12 +2,20 @@ use rand::rngs::OsRng;
 use serde::{Deserialize, Serialize};
 use tracing::error;
 use uuid::Uuid;
+use zeroize::Zeroizing;
 
 use crate::{
-    crypto::aead::encrypt,
+    crypto::servicekeys::ServiceKeys,
     database::{
-        open_database,
-        RecordRepository,
+        RecordRepository, ensure_writable,
+        records::{self, RecordRow, UnreadableRecord},
+    },
+    error::IterateError,
+    services::{
+        entrypayload::{EntryFormat, EntryPayload, EntryPayloadV1, count_words},
+        recordcipher::{
+            EncodingPolicy, RecordContext, RecordKind, journal_id, open_record, seal_record,
+        },
     },
     state::AppState,
 };
@@ -15,74 +23,369 @@ use crate::{
 #[derive(Debug, Serialize, Deserialize)]
 pub struct JournalEntry {
     id: Option<Uuid>,
+    #[serde(default)]
+    title: Option<String>,
     text: String,
+    #[serde(default)]
+    format: EntryFormat,
+    #[serde(default)]
+    prompt: Option<String>,
+    #[serde(default)]
+    mood: Option<u8>,
+    /// Counted on save, any value sent by the frontend is replaced.
+    #[serde(default)]
+    word_count: u32,
+    /// Set when the entry is read, ignored on save.
+    #[serde(default)]
+    created_at_utc: Option<i64>,
+    #[serde(default)]
+    last_modified_at_utc: Option<i64>,
+}
+
+impl JournalEntry {
+    fn to_payload(&self) -> EntryPayload {
+        EntryPayload::V1(EntryPayloadV1 {
+            title: self.title.clone(),
+            text: self.text.clone(),
+            format: self.format,
+            prompt: self.prompt.clone(),
+            mood: self.mood,
+            word_count: count_words(&self.text),
+        })
+    }
+
+    fn from_payload(row: &RecordRow, payload: EntryPayloadV1) -> Self {
+        JournalEntry {
+            id: Some(row.id),
+            title: payload.title,
+            text: payload.text,
+            format: payload.format,
+            prompt: payload.prompt,
+            mood: payload.mood,
+            word_count: payload.word_count,
+            created_at_utc: Some(row.created_at_utc),
+            last_modified_at_utc: Some(row.last_modified_at_utc),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", content = "message")]
+pub enum ReadRecordError {
+    InvalidState,
+    NotFound,
+    DecryptionFailure,
+    /// The record's metadata failed authentication, e.g. because it was changed outside the app.
+    Tampered,
+    InternalError(String),
+    DatabaseFailure(String),
+}
+
+/// One item of `list_journal_entries`. An entry that cannot be decrypted is
+/// reported on its own, so the rest of the list still loads.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", content = "data")]
+pub enum ListedJournalEntry {
+    Entry(JournalEntry),
+    Unreadable { id: Uuid, error: ReadRecordError },
 }
 
+/// Entries returned by `list_journal_entries` unless the frontend asks for a different number.
+const DEFAULT_ENTRY_LIST_LIMIT: usize = 50;
+
 #[derive(Debug, Serialize)]
 #[serde(tag = "type", content = "message")]
 pub enum SaveRecordError {
     InvalidState,
+    ReadOnlyJournal,
     InternalError(String),
     EncryptionFailure,
     DatabaseFailure(String),
 }
 
+fn read_only_error(e: IterateError) -> SaveRecordError {
+    match e {
+        IterateError::ReadOnlyJournal => SaveRecordError::ReadOnlyJournal,
+        e => {
+            error!("Checking the journal access failed: {:?}", e);
+            SaveRecordError::DatabaseFailure("The journal could not be read".to_string())
+        }
+    }
+}
+
 #[tauri::command]
 pub async fn save_journal_entry(
     mut journal_entry: JournalEntry,
     state: tauri::State<'_, AppState>,
 ) -> Result<JournalEntry, SaveRecordError> {
-    let db_path = {
-        let guard = state.db_path.lock();
-        guard
-            .as_ref()
-            .cloned()
-            .ok_or(SaveRecordError::InvalidState)?
-    };
-
-    let conn = open_database(db_path)
-        .map_err(|e| SaveRecordError::DatabaseFailure(format!("Failed to open DB: {}", e)))?;
-
-    let keys_guard = state.serivce_keys.lock();
-    let keys = keys_guard.as_ref().ok_or(SaveRecordError::InvalidState)?;
+    let session = state.session.lock();
+    let (conn, keys) = session.unlocked().ok_or(SaveRecordError::InvalidState)?;
+    ensure_writable(conn).map_err(read_only_error)?;
+    state.record_activity();
 
     let (record_id, is_new) = match journal_entry.id {
         Some(existing_id) => (existing_id, false),
         None => {
-            let new_id = Uuid::now_v7();
+            let new_id = records::new_row_id(conn).map_err(|e| {
+                error!("Reading the privacy mode failed: {:?}", e);
+                SaveRecordError::DatabaseFailure("The journal could not be read".to_string())
+            })?;
             journal_entry.id = Some(new_id);
             (new_id, true)
         }
     };
 
-    let envelope = encrypt(
-        &mut OsRng,
-        &keys.content,
-        journal_entry.text.as_bytes(),
-        record_id.as_bytes(),
-    )
-    .map_err(|_| SaveRecordError::EncryptionFailure)?;
-
-    let encrypted_blob = envelope.to_blob().map_err(|e| {
-        error!("Postcard-Serialization failed: {:?}", e);
+    let (journal_id, encoding) = journal_id(conn)
+        .and_then(|journal_id| Ok((journal_id, EncodingPolicy::load(conn)?)))
+        .map_err(|e| {
+            error!("Reading the journal settings failed: {:?}", e);
+            SaveRecordError::DatabaseFailure("The journal could not be read".to_string())
+        })?;
+    let context = RecordContext {
+        journal_id,
+        record_id,
+        kind: RecordKind::Entry,
+    };
 
-        return SaveRecordError::InternalError("Unexpected error occured".to_string());
-    })?;
+    journal_entry.word_count = count_words(&journal_entry.text);
+    let sealed = journal_entry
+        .to_payload()
+        .to_bytes()
+        .and_then(|payload| seal_record(&mut OsRng, keys, &context, &payload, &encoding))
+        .map_err(|e| match e {
+            IterateError::PostCardSerializationFailed(_) => {
+                error!("Postcard-Serialization failed: {:?}", e);
+                SaveRecordError::InternalError("Unexpected error occured".to_string())
+            }
+            _ => SaveRecordError::EncryptionFailure,
+        })?;
 
-    let record_repository = RecordRepository::new(&conn);
+    let record_repository = RecordRepository::new(conn, keys);
 
     if is_new {
-        record_repository.insert(&record_id, &encrypted_blob, None)
+        record_repository
+            .insert(
+                &mut OsRng,
+                &record_id,
+                &sealed.encrypted_content,
+                &sealed.wrapped_data_key,
+                None,
+            )
             .map_err(|e| {
                 error!("SQL Insert failed: {:?}", e);
                 return SaveRecordError::DatabaseFailure("Record was not saved".to_string());
             })?;
     } else {
-        record_repository.update(&record_id, &encrypted_blob).map_err(|e| {
-            error!("SQL Update failed: {:?}", e);
-            return SaveRecordError::DatabaseFailure("Record was not saved".to_string());
-        })?;
+        record_repository
+            .update(
+                &mut OsRng,
+                &record_id,
+                &sealed.encrypted_content,
+                &sealed.wrapped_data_key,
+            )
+            .map_err(|e| {
+                error!("SQL Update failed: {:?}", e);
+                return SaveRecordError::DatabaseFailure("Record was not saved".to_string());
+            })?;
     }
 
     Ok(journal_entry)
 }
+
+/// Deletes an entry for good. Its data key is destroyed with the row,
+/// so the ciphertext cannot be recovered afterwards.
+#[tauri::command]
+pub async fn delete_journal_entry_permanently(
+    id: Uuid,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), SaveRecordError> {
+    let session = state.session.lock();
+    let (conn, keys) = session.unlocked().ok_or(SaveRecordError::InvalidState)?;
+    ensure_writable(conn).map_err(read_only_error)?;
+
+    RecordRepository::new(conn, keys)
+        .delete_permanently(&id)
+        .map_err(|e| {
+            error!("SQL Delete failed: {:?}", e);
+            SaveRecordError::DatabaseFailure("Record was not deleted".to_string())
+        })
+}
+
+/// Decrypts and returns one entry.
+#[tauri::command]
+pub async fn get_journal_entry(
+    id: Uuid,
+    state: tauri::State<'_, AppState>,
+) -> Result<JournalEntry, ReadRecordError> {
+    let session = state.session.lock();
+    let (conn, keys) = session.unlocked().ok_or(ReadRecordError::InvalidState)?;
+    state.record_activity();
+
+    let journal_id = journal_id(conn).map_err(|e| {
+        error!("Reading the journal id failed: {:?}", e);
+        ReadRecordError::DatabaseFailure("The journal could not be read".to_string())
+    })?;
+    let row = RecordRepository::new(conn, keys)
+        .get_record(id)
+        .map_err(|e| open_error(id, e))?;
+    if row.is_deleted {
+        return Err(ReadRecordError::NotFound);
+    }
+
+    read_entry(keys, journal_id, &row)
+}
+
+/// Returns the newest entries, newest first. Summaries and deleted entries are left out.
+/// `limit` is capped at `records::MAX_LATEST_RECORDS`.
+#[tauri::command]
+pub async fn list_journal_entries(
+    limit: Option<usize>,
+    state: tauri::State<'_, AppState>,
+) -> Result<Vec<ListedJournalEntry>, ReadRecordError> {
+    let session = state.session.lock();
+    let (conn, keys) = session.unlocked().ok_or(ReadRecordError::InvalidState)?;
+    state.record_activity();
+
+    let journal_id = journal_id(conn).map_err(|e| {
+        error!("Reading the journal id failed: {:?}", e);
+        ReadRecordError::DatabaseFailure("The journal could not be read".to_string())
+    })?;
+    let rows = RecordRepository::new(conn, keys)
+        .fetch_latest(limit.unwrap_or(DEFAULT_ENTRY_LIST_LIMIT))
+        .map_err(|e| {
+            error!("SQL Select failed: {:?}", e);
+            ReadRecordError::DatabaseFailure("Records were not read".to_string())
+        })?;
+
+    Ok(listed_entries(keys, journal_id, rows))
+}
+
+fn listed_entries(
+    keys: &ServiceKeys,
+    journal_id: Uuid,
+    rows: Vec<Result<RecordRow, UnreadableRecord>>,
+) -> Vec<ListedJournalEntry> {
+    rows.into_iter()
+        .map(|row| match row {
+            Ok(row) => match read_entry(keys, journal_id, &row) {
+                Ok(entry) => ListedJournalEntry::Entry(entry),
+                Err(error) => ListedJournalEntry::Unreadable { id: row.id, error },
+            },
+            Err(UnreadableRecord { id, error }) => ListedJournalEntry::Unreadable {
+                id,
+                error: open_error(id, error),
+            },
+        })
+        .collect()
+}
+
+/// Maps a record that could not be read or authenticated, the same way for a single
+/// entry and for one in a list.
+fn open_error(id: Uuid, e: IterateError) -> ReadRecordError {
+    match e {
+        IterateError::RecordNotFound => ReadRecordError::NotFound,
+        IterateError::RecordTampered => ReadRecordError::Tampered,
+        IterateError::AeadIntegrityFailure | IterateError::DecryptionFailed(_) => {
+            error!("Decrypting record {} failed: {:?}", id, e);
+            ReadRecordError::DecryptionFailure
+        }
+        e => {
+            error!("Reading record {} failed: {:?}", id, e);
+            ReadRecordError::DatabaseFailure("Record was not read".to_string())
+        }
+    }
+}
+
+fn read_entry(
+    keys: &ServiceKeys,
+    journal_id: Uuid,
+    row: &RecordRow,
+) -> Result<JournalEntry, ReadRecordError> {
+    let context = RecordContext {
+        journal_id,
+        record_id: row.id,
+        kind: RecordKind::of(row.is_summary_record),
+    };
+
+    let content = open_record(
+        keys,
+        &context,
+        &row.encrypted_content,
+        row.wrapped_data_key.as_deref(),
+    )
+    .map(Zeroizing::new)
+    .map_err(|e| {
+        error!("Decrypting record {} failed: {:?}", row.id, e);
+        ReadRecordError::DecryptionFailure
+    })?;
+    let payload = EntryPayload::from_bytes(&content).map_err(|e| {
+        error!("Reading the payload of record {} failed: {:?}", row.id, e);
+        ReadRecordError::InternalError("The entry could not be read".to_string())
+    })?;
+
+    Ok(JournalEntry::from_payload(row, payload.into_latest()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::open_database;
+    use crate::database::testing::{TempJournal, service_keys};
+    use rusqlite::params;
+
+    #[test]
+    fn lists_an_entry_with_a_broken_mac_as_tampered() {
+        let journal = TempJournal::in_temp_dir();
+        let conn = open_database(journal.0.clone()).unwrap();
+        let keys = service_keys();
+        let records = RecordRepository::new(&conn, &keys);
+        records.authenticate_legacy_rows().unwrap();
+        let journal_id = journal_id(&conn).unwrap();
+        let encoding = EncodingPolicy::load(&conn).unwrap();
+
+        let mut ids = Vec::new();
+        for text in ["first", "second"] {
+            let id = Uuid::now_v7();
+            let context = RecordContext {
+                journal_id,
+                record_id: id,
+                kind: RecordKind::Entry,
+            };
+            let payload = EntryPayload::from_bytes(text.as_bytes())
+                .unwrap()
+                .to_bytes()
+                .unwrap();
+            let sealed = seal_record(&mut OsRng, &keys, &context, &payload, &encoding).unwrap();
+            records
+                .insert(
+                    &mut OsRng,
+                    &id,
+                    &sealed.encrypted_content,
+                    &sealed.wrapped_data_key,
+                    None,
+                )
+                .unwrap();
+            ids.push(id);
+        }
+        conn.execute(
+            "UPDATE record SET metadata_mac = x'00' WHERE record_id = ?",
+            params![ids[0].as_bytes()],
+        )
+        .unwrap();
+
+        let listed = listed_entries(&keys, journal_id, records.fetch_latest(10).unwrap());
+        assert_eq!(listed.len(), 2);
+        assert!(
+            listed
+                .iter()
+                .any(|entry| matches!(entry, ListedJournalEntry::Entry(_)))
+        );
+        assert!(listed.iter().any(|entry| matches!(
+            entry,
+            ListedJournalEntry::Unreadable {
+                id,
+                error: ReadRecordError::Tampered,
+            } if *id == ids[0]
+        )));
+    }
+}
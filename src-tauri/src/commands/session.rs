@@ -0,0 +1,95 @@
+use serde::Serialize;
+use std::thread;
+use std::time::Duration;
+use tauri::{Emitter, Manager};
+use tracing::{error, info};
+
+use crate::state::AppState;
+
+pub const JOURNAL_LOCKED_EVENT: &str = "journal-locked";
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LockReason {
+    Manual,
+    Idle,
+    Exit,
+    /// Another instance took over the journal lock, e.g. after a long suspend.
+    LockLost,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", content = "message")]
+pub enum LockError {
+    /// A long command such as a key rotation holds the journal. It can be locked once that finished.
+    Busy,
+}
+
+/// Locks the journal. The service keys are dropped and zeroized, so nothing can be
+/// decrypted until the journal is unlocked again. The journal itself stays open.
+/// Rather than blocking the UI until a long command is done, this reports `Busy`.
+#[tauri::command]
+pub fn lock_journal(
+    app: tauri::AppHandle,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), LockError> {
+    match state.try_lock_journal() {
+        Some(locked) => {
+            if locked {
+                notify_locked(&app, LockReason::Manual);
+            }
+            Ok(())
+        }
+        None => Err(LockError::Busy),
+    }
+}
+
+/// Resets the idle timer. The frontend calls this on user input.
+#[tauri::command]
+pub fn record_activity(state: tauri::State<'_, AppState>) {
+    state.record_activity();
+}
+
+/// Locks an unlocked journal once it has been idle for `AppConfig::idle_lock_minutes`.
+/// Tauri does not report OS session locks or suspends, but the idle time is measured
+/// on the wall clock, so a journal left unlocked over a suspend is locked right after wake-up.
+/// Each check also refreshes the journal lock, and locks the journal if it was lost.
+/// A journal whose session a command holds, e.g. during a key rotation, is in use rather than idle.
+pub fn spawn_idle_watcher(app: tauri::AppHandle) {
+    thread::spawn(move || {
+        loop {
+            thread::sleep(IDLE_CHECK_INTERVAL);
+            let state = app.state::<AppState>();
+            if !state.refresh_journal_lock() {
+                lock(&app, &state, LockReason::LockLost);
+            } else if state.is_idle() {
+                match state.try_lock_journal() {
+                    Some(true) => notify_locked(&app, LockReason::Idle),
+                    Some(false) => {}
+                    None => state.record_activity(),
+                }
+            }
+        }
+    });
+}
+
+/// Drops the keys before the process exits.
+pub fn handle_run_event(app: &tauri::AppHandle, event: tauri::RunEvent) {
+    if let tauri::RunEvent::Exit = event {
+        lock(app, &app.state::<AppState>(), LockReason::Exit);
+    }
+}
+
+fn lock(app: &tauri::AppHandle, state: &AppState, reason: LockReason) {
+    if state.lock_journal() {
+        notify_locked(app, reason);
+    }
+}
+
+fn notify_locked(app: &tauri::AppHandle, reason: LockReason) {
+    info!("Journal locked: {:?}", reason);
+    if let Err(e) = app.emit(JOURNAL_LOCKED_EVENT, reason) {
+        error!("Emitting the lock event failed: {}", e);
+    }
+}
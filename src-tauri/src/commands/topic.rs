@@ -0,0 +1,117 @@
+use rand::rngs::OsRng;
+use serde::Serialize;
+use tracing::error;
+use uuid::Uuid;
+
+use crate::{
+    database::{
+        TopicRepository, ensure_writable,
+        topics::{PromptRow, TopicRow},
+    },
+    error::IterateError,
+    state::AppState,
+};
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", content = "message")]
+pub enum TopicError {
+    InvalidState,
+    DuplicateName,
+    NotFound,
+    ReadOnlyJournal,
+    DatabaseFailure(String),
+}
+
+fn topic_error(e: IterateError) -> TopicError {
+    match e {
+        IterateError::DuplicateTopic => TopicError::DuplicateName,
+        IterateError::TopicNotFound => TopicError::NotFound,
+        IterateError::ReadOnlyJournal => TopicError::ReadOnlyJournal,
+        e => {
+            error!("Topic operation failed: {:?}", e);
+            TopicError::DatabaseFailure("The topic could not be accessed".to_string())
+        }
+    }
+}
+
+#[tauri::command]
+pub async fn create_topic(
+    name: String,
+    note: Option<String>,
+    state: tauri::State<'_, AppState>,
+) -> Result<Uuid, TopicError> {
+    let session = state.session.lock();
+    let (conn, keys) = session.unlocked().ok_or(TopicError::InvalidState)?;
+    ensure_writable(conn).map_err(topic_error)?;
+
+    TopicRepository::new(conn, keys)
+        .insert_topic(&mut OsRng, &name, note.as_deref())
+        .map_err(topic_error)
+}
+
+#[tauri::command]
+pub async fn update_topic(
+    id: Uuid,
+    name: String,
+    note: Option<String>,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), TopicError> {
+    let session = state.session.lock();
+    let (conn, keys) = session.unlocked().ok_or(TopicError::InvalidState)?;
+    ensure_writable(conn).map_err(topic_error)?;
+
+    TopicRepository::new(conn, keys)
+        .update_topic(&mut OsRng, &id, &name, note.as_deref())
+        .map_err(topic_error)
+}
+
+/// Deletes the topic together with its prompts and entry tags.
+#[tauri::command]
+pub async fn delete_topic(id: Uuid, state: tauri::State<'_, AppState>) -> Result<(), TopicError> {
+    let session = state.session.lock();
+    let (conn, keys) = session.unlocked().ok_or(TopicError::InvalidState)?;
+    ensure_writable(conn).map_err(topic_error)?;
+
+    TopicRepository::new(conn, keys)
+        .delete_topic(&id)
+        .map_err(topic_error)
+}
+
+#[tauri::command]
+pub async fn list_topics(state: tauri::State<'_, AppState>) -> Result<Vec<TopicRow>, TopicError> {
+    let session = state.session.lock();
+    let (conn, keys) = session.unlocked().ok_or(TopicError::InvalidState)?;
+
+    TopicRepository::new(conn, keys)
+        .list_topics()
+        .map_err(topic_error)
+}
+
+#[tauri::command]
+pub async fn create_prompt(
+    topic_id: Uuid,
+    text: String,
+    is_default: bool,
+    state: tauri::State<'_, AppState>,
+) -> Result<Uuid, TopicError> {
+    let session = state.session.lock();
+    let (conn, keys) = session.unlocked().ok_or(TopicError::InvalidState)?;
+    ensure_writable(conn).map_err(topic_error)?;
+
+    TopicRepository::new(conn, keys)
+        .insert_prompt(&mut OsRng, &topic_id, &text, is_default)
+        .map_err(topic_error)
+}
+
+#[tauri::command]
+pub async fn list_prompts(
+    topic_id: Uuid,
+    state: tauri::State<'_, AppState>,
+) -> Result<Vec<PromptRow>, TopicError> {
+    let session = state.session.lock();
+    let (conn, keys) = session.unlocked().ok_or(TopicError::InvalidState)?;
+
+    TopicRepository::new(conn, keys)
+        .list_prompts(&topic_id)
+        .map_err(topic_error)
+}
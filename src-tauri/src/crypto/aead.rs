@@ -1,44 +1,59 @@
-use crate::crypto::constants::{AES_GCM_NONCE_LEN, AES_GCM_TAG_LEN};
-use crate::crypto::cryptoenvelope::{AesGcmPacked, CryptoEnvelope};
+use crate::crypto::compression::Compression;
+use crate::crypto::constants::{XCHACHA_NONCE_LEN, XCHACHA_TAG_LEN};
+use crate::crypto::cryptoenvelope::{CryptoEnvelope, XChaChaEncoded, XChaChaPacked};
+use crate::crypto::padding::Padding;
 use crate::error::IterateError;
 use aes_gcm::{
     Aes256Gcm, Key, Nonce,
     aead::{AeadMutInPlace, KeyInit},
 };
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
 use rand::TryRngCore;
 use zeroize::Zeroizing;
 
-/// Encrypts the plaintext using AES-256-GCM with the provided key and AAD.
-/// Returns (ciphertext, nonce, tag).
+/// Encrypts the plaintext using XChaCha20-Poly1305 with the provided key and AAD.
+/// The 192-bit random nonce makes collisions negligible even after many writes under one key.
+/// The envelope version is authenticated together with the AAD.
 pub fn encrypt<R: TryRngCore>(
     rng: &mut R, // Inject RNG here
     key: &Zeroizing<[u8; 32]>,
     plaintext: &[u8],
     associated_data: &[u8],
 ) -> Result<CryptoEnvelope, IterateError> {
-    let mut nonce = [0u8; AES_GCM_NONCE_LEN];
-    rng.try_fill_bytes(&mut nonce)
-        .map_err(|_| IterateError::SystemRngFailure)?;
-
-    let mut cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key.as_ref()));
-    let mut buffer = plaintext.to_vec();
+    let aad = versioned_aad(CryptoEnvelope::CURRENT_VERSION, &[], associated_data);
 
-    let tag = cipher
-        .encrypt_in_place_detached(Nonce::from_slice(&nonce), associated_data, &mut buffer)
-        .map_err(|_| IterateError::AeadIntegrityFailure)?;
+    Ok(CryptoEnvelope::V2(xchacha_encrypt(
+        rng, key, plaintext, &aad,
+    )?))
+}
 
-    let mut tag_arr = [0u8; AES_GCM_TAG_LEN];
-    tag_arr.copy_from_slice(&tag);
+/// Like `encrypt`, but compresses and then pads the plaintext first, so the ciphertext
+/// length only reveals the bucket chosen by `padding`. Produces a V3 envelope.
+pub fn encrypt_encoded<R: TryRngCore>(
+    rng: &mut R,
+    key: &Zeroizing<[u8; 32]>,
+    plaintext: &[u8],
+    associated_data: &[u8],
+    compression: Compression,
+    padding: Padding,
+) -> Result<CryptoEnvelope, IterateError> {
+    let (compression, compressed) = compression.compress(plaintext)?;
+    let padded = padding.pad(&compressed);
+    let aad = versioned_aad(
+        CryptoEnvelope::ENCODED_VERSION,
+        &[compression as u8, padding as u8],
+        associated_data,
+    );
 
-    Ok(CryptoEnvelope::V1(AesGcmPacked {
-        nonce,
-        ciphertext: buffer,
-        tag: tag_arr,
+    Ok(CryptoEnvelope::V3(XChaChaEncoded {
+        compression,
+        padding,
+        sealed: xchacha_encrypt(rng, key, &padded, &aad)?,
     }))
 }
 
-/// Encrypts the plaintext using AES-256-GCM with the provided key and AAD.
-/// Returns serialized blob of CryptoEnvelope(version, ciphertext, nonce, tag).
+/// Decrypts an envelope of any supported version with the provided key and AAD.
+/// V1 (AES-256-GCM) envelopes written by older versions stay readable.
 pub fn decrypt(
     key: &Zeroizing<[u8; 32]>,
     envelope: &CryptoEnvelope,
@@ -60,5 +75,80 @@ pub fn decrypt(
 
             Ok(buffer)
         }
+        CryptoEnvelope::V2(data) => xchacha_decrypt(
+            key,
+            data,
+            &versioned_aad(envelope.version(), &[], associated_data),
+        ),
+        CryptoEnvelope::V3(data) => {
+            let padded = xchacha_decrypt(
+                key,
+                &data.sealed,
+                &versioned_aad(
+                    envelope.version(),
+                    &[data.compression as u8, data.padding as u8],
+                    associated_data,
+                ),
+            )?;
+
+            data.compression.decompress(data.padding.unpad(padded)?)
+        }
     }
 }
+
+fn xchacha_encrypt<R: TryRngCore>(
+    rng: &mut R,
+    key: &Zeroizing<[u8; 32]>,
+    plaintext: &[u8],
+    associated_data: &[u8],
+) -> Result<XChaChaPacked, IterateError> {
+    let mut nonce = [0u8; XCHACHA_NONCE_LEN];
+    rng.try_fill_bytes(&mut nonce)
+        .map_err(|_| IterateError::SystemRngFailure)?;
+
+    let mut cipher = XChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(key.as_ref()));
+    let mut buffer = plaintext.to_vec();
+
+    let tag = cipher
+        .encrypt_in_place_detached(XNonce::from_slice(&nonce), associated_data, &mut buffer)
+        .map_err(|_| IterateError::AeadIntegrityFailure)?;
+
+    let mut tag_arr = [0u8; XCHACHA_TAG_LEN];
+    tag_arr.copy_from_slice(&tag);
+
+    Ok(XChaChaPacked {
+        nonce,
+        ciphertext: buffer,
+        tag: tag_arr,
+    })
+}
+
+fn xchacha_decrypt(
+    key: &Zeroizing<[u8; 32]>,
+    data: &XChaChaPacked,
+    associated_data: &[u8],
+) -> Result<Vec<u8>, IterateError> {
+    let mut cipher = XChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(key.as_ref()));
+    let mut buffer = data.ciphertext.clone();
+
+    cipher
+        .decrypt_in_place_detached(
+            XNonce::from_slice(&data.nonce),
+            associated_data,
+            &mut buffer,
+            (&data.tag).into(),
+        )
+        .map_err(|_| IterateError::AeadIntegrityFailure)?;
+
+    Ok(buffer)
+}
+
+/// Prefixes the caller's AAD with the envelope version and the envelope's header fields,
+/// so neither can be changed without failing authentication.
+fn versioned_aad(version: u8, header: &[u8], associated_data: &[u8]) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(1 + header.len() + associated_data.len());
+    aad.push(version);
+    aad.extend_from_slice(header);
+    aad.extend_from_slice(associated_data);
+    aad
+}
@@ -0,0 +1,118 @@
+use crate::crypto::constants::{
+    ARGON2_MAX_MEMORY_COST, ARGON2_MAX_TIME_COST, ARGON2_MIN_MEMORY_COST, ARGON2_MIN_TIME_COST,
+    ARGON2_PARALLELISM, ARGON2_SALT_LEN, ARGON2_TARGET_UNLOCK_MS, MASTER_KEY_LEN,
+};
+use crate::crypto::kdf::hash_argon2id;
+use crate::error::IterateError;
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Argon2Cost {
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+impl Argon2Cost {
+    /// The floor every journal is held to, regardless of how slow the host is.
+    pub const MINIMUM: Self = Self {
+        m_cost: ARGON2_MIN_MEMORY_COST,
+        t_cost: ARGON2_MIN_TIME_COST,
+        p_cost: ARGON2_PARALLELISM,
+    };
+}
+
+static CALIBRATED_COST: OnceLock<Argon2Cost> = OnceLock::new();
+
+/// Returns the Argon2 cost for this machine.
+/// The benchmark runs once per process; if it fails the minimum cost is used.
+/// Tests run at the minimum cost rather than benchmarking the machine.
+pub fn calibrated_cost() -> Argon2Cost {
+    *CALIBRATED_COST.get_or_init(|| {
+        if cfg!(test) {
+            return Argon2Cost::MINIMUM;
+        }
+        match calibrate(Duration::from_millis(ARGON2_TARGET_UNLOCK_MS)) {
+            Ok(cost) => {
+                info!("Argon2 calibrated: {:?}", cost);
+                cost
+            }
+            Err(e) => {
+                warn!("Argon2 calibration failed, using minimum cost: {}", e);
+                Argon2Cost::MINIMUM
+            }
+        }
+    })
+}
+
+/// Runs the benchmark on a background thread at startup, so that no command waits for it.
+pub fn calibrate_in_background() {
+    thread::spawn(calibrated_cost);
+}
+
+/// The calibrated cost, or the minimum while the benchmark is still running.
+/// Unlocking uses this and never waits for the benchmark.
+pub fn calibrated_cost_if_ready() -> Argon2Cost {
+    CALIBRATED_COST
+        .get()
+        .copied()
+        .unwrap_or(Argon2Cost::MINIMUM)
+}
+
+/// Benchmarks Argon2id and picks the largest cost that stays within `target`.
+/// Memory is raised first (doubling) up to `ARGON2_MAX_MEMORY_COST`, the remaining
+/// budget goes into passes.
+pub fn calibrate(target: Duration) -> Result<Argon2Cost, IterateError> {
+    let mut cost = Argon2Cost::MINIMUM;
+    let mut elapsed = measure(&cost)?;
+
+    while cost.m_cost < ARGON2_MAX_MEMORY_COST && elapsed * 2 <= target {
+        let candidate = Argon2Cost {
+            m_cost: (cost.m_cost * 2).min(ARGON2_MAX_MEMORY_COST),
+            ..cost
+        };
+        // Memory the machine cannot give ends the search like a slow candidate would.
+        elapsed = match measure(&candidate) {
+            Err(IterateError::InsufficientKdfMemory) => break,
+            result => result?,
+        };
+        if elapsed > target {
+            break;
+        }
+        cost = candidate;
+    }
+
+    // Argon2 time scales linearly with the number of passes.
+    let per_pass = measure(&cost)? / cost.t_cost;
+    if !per_pass.is_zero() {
+        let passes = (target.as_nanos() / per_pass.as_nanos()) as u32;
+        cost.t_cost = passes.clamp(ARGON2_MIN_TIME_COST, ARGON2_MAX_TIME_COST);
+    }
+
+    Ok(cost)
+}
+
+fn measure(cost: &Argon2Cost) -> Result<Duration, IterateError> {
+    let mut out = [0u8; MASTER_KEY_LEN];
+
+    let start = Instant::now();
+    hash_argon2id(cost, b"calibration", &[0u8; ARGON2_SALT_LEN], &mut out)?;
+
+    Ok(start.elapsed())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_minimum_cost_when_the_target_is_tight() {
+        let cost = calibrate(Duration::from_millis(1)).unwrap();
+
+        assert_eq!(cost, Argon2Cost::MINIMUM);
+        assert_eq!(calibrated_cost(), Argon2Cost::MINIMUM);
+    }
+}
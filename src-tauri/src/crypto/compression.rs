@@ -0,0 +1,109 @@
+use crate::crypto::constants::{MAX_INFLATED_LEN, ZSTD_LEVEL};
+use crate::error::IterateError;
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+use zeroize::Zeroizing;
+
+/// Compression applied to plaintext before it is padded and encrypted.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[repr(u8)]
+pub enum Compression {
+    None = 0,
+    Zstd = 1,
+}
+
+impl Compression {
+    pub fn from_id(id: i64) -> Option<Self> {
+        match id {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Compresses the plaintext and returns the compression actually used.
+    /// Falls back to `None` if compressing does not make the plaintext smaller,
+    /// or if it could not be inflated again within `MAX_INFLATED_LEN`.
+    pub fn compress(
+        &self,
+        plaintext: &[u8],
+    ) -> Result<(Compression, Zeroizing<Vec<u8>>), IterateError> {
+        if *self == Compression::None || plaintext.len() > MAX_INFLATED_LEN {
+            return Ok((Compression::None, Zeroizing::new(plaintext.to_vec())));
+        }
+
+        let compressed = Zeroizing::new(
+            zstd::bulk::compress(plaintext, ZSTD_LEVEL)
+                .map_err(|e| IterateError::Internal(e.to_string()))?,
+        );
+        if compressed.len() >= plaintext.len() {
+            return Ok((Compression::None, Zeroizing::new(plaintext.to_vec())));
+        }
+
+        Ok((Compression::Zstd, compressed))
+    }
+
+    /// Inflates data written by `compress`. Stops at `MAX_INFLATED_LEN`, so a crafted
+    /// payload cannot exhaust memory.
+    pub fn decompress(&self, data: Vec<u8>) -> Result<Vec<u8>, IterateError> {
+        if *self == Compression::None {
+            return Ok(data);
+        }
+
+        let mut inflated = Vec::new();
+        zstd::stream::read::Decoder::new(data.as_slice())
+            .and_then(|decoder| {
+                decoder
+                    .take(MAX_INFLATED_LEN as u64 + 1)
+                    .read_to_end(&mut inflated)
+            })
+            .map_err(|e| IterateError::DecryptionFailed(e.to_string()))?;
+
+        if inflated.len() > MAX_INFLATED_LEN {
+            return Err(IterateError::DecompressionLimitExceeded);
+        }
+        Ok(inflated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decompress_restores_what_compress_wrote() {
+        let plaintext = "A quiet morning, the same quiet morning. ".repeat(100);
+
+        let (compression, compressed) = Compression::Zstd.compress(plaintext.as_bytes()).unwrap();
+        assert_eq!(compression, Compression::Zstd);
+        assert!(compressed.len() < plaintext.len());
+        assert_eq!(
+            compression.decompress(compressed.to_vec()).unwrap(),
+            plaintext.as_bytes()
+        );
+    }
+
+    #[test]
+    fn keeps_plaintext_that_does_not_shrink() {
+        let (compression, stored) = Compression::Zstd.compress(b"short").unwrap();
+        assert_eq!(compression, Compression::None);
+        assert_eq!(stored.as_slice(), b"short");
+    }
+
+    #[test]
+    fn inflates_up_to_the_limit_and_no_further() {
+        let at_limit = zstd::bulk::compress(&vec![0u8; MAX_INFLATED_LEN], ZSTD_LEVEL).unwrap();
+        assert_eq!(
+            Compression::Zstd.decompress(at_limit).unwrap().len(),
+            MAX_INFLATED_LEN
+        );
+
+        let bomb = zstd::bulk::compress(&vec![0u8; MAX_INFLATED_LEN + 1], ZSTD_LEVEL).unwrap();
+        assert!(bomb.len() < 4096);
+        assert!(matches!(
+            Compression::Zstd.decompress(bomb),
+            Err(IterateError::DecompressionLimitExceeded)
+        ));
+    }
+}
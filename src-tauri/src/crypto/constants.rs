@@ -1,10 +1,37 @@
 // ── aead ─────────────────────────────────────────────────────────
 pub(crate) const AES_GCM_NONCE_LEN: usize = 12;
 pub(crate) const AES_GCM_TAG_LEN: usize = 16;
+pub(crate) const XCHACHA_NONCE_LEN: usize = 24;
+pub(crate) const XCHACHA_TAG_LEN: usize = 16;
 
 // ── kdf ─────────────────────────────────────────────────────────
 pub(crate) const MASTER_KEY_LEN: usize = 32;
-pub(crate) const ARGON2_MEMORY_COST: u32 = 64 * 1024;
-pub(crate) const ARGON2_TIME_COST: u32 = 3;
+pub(crate) const ARGON2_MIN_MEMORY_COST: u32 = 64 * 1024;
+/// 256 MiB. Calibration stops here, so a journal synced to a weaker machine still unlocks.
+pub(crate) const ARGON2_MAX_MEMORY_COST: u32 = 256 * 1024;
+pub(crate) const ARGON2_MIN_TIME_COST: u32 = 3;
+pub(crate) const ARGON2_MAX_TIME_COST: u32 = 16;
 pub(crate) const ARGON2_PARALLELISM: u32 = 4;
 pub(crate) const ARGON2_SALT_LEN: usize = 16;
+pub(crate) const ARGON2_TARGET_UNLOCK_MS: u64 = 1000;
+pub(crate) const ARGON2_UPGRADE_THRESHOLD_PERCENT: u64 = 75;
+
+// ── records ─────────────────────────────────────────────────────────
+pub(crate) const DATA_KEY_LEN: usize = 32;
+pub(crate) const MAC_LEN: usize = 32;
+
+// ── keyfile ─────────────────────────────────────────────────────────
+pub(crate) const KEYFILE_SALT_LEN: usize = 16;
+pub(crate) const KEYFILE_CHECK_LEN: usize = 32;
+
+// ── recovery ─────────────────────────────────────────────────────────
+pub(crate) const RECOVERY_KEY_LEN: usize = 32;
+pub(crate) const RECOVERY_SALT_LEN: usize = 16;
+
+// ── padding ─────────────────────────────────────────────────────────
+pub(crate) const PADDING_MIN_BUCKET: usize = 64;
+pub(crate) const PADDING_MARKER: u8 = 0x80;
+
+// ── compression ─────────────────────────────────────────────────────────
+pub(crate) const ZSTD_LEVEL: i32 = 3;
+pub(crate) const MAX_INFLATED_LEN: usize = 16 * 1024 * 1024;
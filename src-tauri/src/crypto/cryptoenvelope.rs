@@ -1,12 +1,23 @@
-use crate::crypto::constants::{AES_GCM_NONCE_LEN, AES_GCM_TAG_LEN};
+use crate::crypto::compression::Compression;
+use crate::crypto::constants::{
+    AES_GCM_NONCE_LEN, AES_GCM_TAG_LEN, XCHACHA_NONCE_LEN, XCHACHA_TAG_LEN,
+};
+use crate::crypto::padding::Padding;
 use crate::error::IterateError;
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[repr(u8)]
 pub enum CryptoEnvelope {
+    /// AES-256-GCM, written by older versions and only read.
     #[serde(rename = "1")]
     V1(AesGcmPacked) = 1,
+    /// XChaCha20-Poly1305, with the envelope version authenticated as part of the AAD.
+    #[serde(rename = "2")]
+    V2(XChaChaPacked) = 2,
+    /// V2 over compressed and padded plaintext. Compression and padding are both authenticated.
+    #[serde(rename = "3")]
+    V3(XChaChaEncoded) = 3,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -16,7 +27,34 @@ pub struct AesGcmPacked {
     pub tag: [u8; AES_GCM_TAG_LEN],
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct XChaChaPacked {
+    pub nonce: [u8; XCHACHA_NONCE_LEN],
+    pub ciphertext: Vec<u8>,
+    pub tag: [u8; XCHACHA_TAG_LEN],
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct XChaChaEncoded {
+    pub compression: Compression,
+    pub padding: Padding,
+    pub sealed: XChaChaPacked,
+}
+
 impl CryptoEnvelope {
+    /// The version `aead::encrypt` writes.
+    pub const CURRENT_VERSION: u8 = 2;
+    /// The version `aead::encrypt_encoded` writes.
+    pub const ENCODED_VERSION: u8 = 3;
+
+    pub fn version(&self) -> u8 {
+        match self {
+            CryptoEnvelope::V1(_) => 1,
+            CryptoEnvelope::V2(_) => 2,
+            CryptoEnvelope::V3(_) => 3,
+        }
+    }
+
     pub fn to_blob(&self) -> Result<Vec<u8>, IterateError> {
         postcard::to_stdvec(&self)
             .map_err(|e| IterateError::PostCardSerializationFailed(e.to_string()))
@@ -0,0 +1,53 @@
+use crate::crypto::aead::{decrypt, encrypt};
+use crate::crypto::constants::DATA_KEY_LEN;
+use crate::crypto::cryptoenvelope::CryptoEnvelope;
+use crate::error::IterateError;
+use rand::TryRngCore;
+use zeroize::Zeroizing;
+
+/// Random key that encrypts exactly one record.
+/// It is stored wrapped by the content key next to the record, so destroying
+/// the wrapped key leaves the record ciphertext undecryptable.
+pub struct DataKey {
+    key: Zeroizing<[u8; DATA_KEY_LEN]>,
+}
+
+impl DataKey {
+    pub fn generate<R: TryRngCore>(rng: &mut R) -> Result<Self, IterateError> {
+        let mut key = Zeroizing::new([0u8; DATA_KEY_LEN]);
+        rng.try_fill_bytes(key.as_mut())
+            .map_err(|_| IterateError::SystemRngFailure)?;
+
+        Ok(Self { key })
+    }
+
+    pub fn wrap<R: TryRngCore>(
+        &self,
+        rng: &mut R,
+        content_key: &Zeroizing<[u8; 32]>,
+        associated_data: &[u8],
+    ) -> Result<CryptoEnvelope, IterateError> {
+        encrypt(rng, content_key, self.key.as_ref(), associated_data)
+    }
+
+    pub fn unwrap(
+        content_key: &Zeroizing<[u8; 32]>,
+        envelope: &CryptoEnvelope,
+        associated_data: &[u8],
+    ) -> Result<Self, IterateError> {
+        let key_vec = Zeroizing::new(decrypt(content_key, envelope, associated_data)?);
+
+        let key: [u8; DATA_KEY_LEN] = key_vec
+            .as_slice()
+            .try_into()
+            .map_err(|_| IterateError::DecryptionFailed("Data key length mismatch".into()))?;
+
+        Ok(Self {
+            key: Zeroizing::new(key),
+        })
+    }
+
+    pub fn key(&self) -> &Zeroizing<[u8; DATA_KEY_LEN]> {
+        &self.key
+    }
+}
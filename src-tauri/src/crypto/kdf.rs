@@ -1,9 +1,11 @@
+use crate::crypto::calibration::{Argon2Cost, calibrated_cost, calibrated_cost_if_ready};
 use crate::crypto::constants::{
-    ARGON2_MEMORY_COST, ARGON2_PARALLELISM, ARGON2_SALT_LEN, ARGON2_TIME_COST, MASTER_KEY_LEN,
+    ARGON2_SALT_LEN, ARGON2_UPGRADE_THRESHOLD_PERCENT, KEYFILE_CHECK_LEN, KEYFILE_SALT_LEN,
+    MASTER_KEY_LEN, RECOVERY_SALT_LEN,
 };
 use crate::crypto::servicekeys::ServiceKeys;
 use crate::error::IterateError;
-use argon2::{Algorithm, Argon2, Params, Version};
+use argon2::{Algorithm, Argon2, Block, Params, Version};
 use hkdf::Hkdf;
 use rand::TryRngCore;
 use serde::{Deserialize, Serialize};
@@ -18,22 +20,154 @@ pub enum KdfParams {
         t_cost: u32,
         p_cost: u32,
     },
+    /// The secret is a high-entropy recovery key, so a plain HKDF is sufficient.
+    RecoveryKeyV1 { salt: [u8; RECOVERY_SALT_LEN] },
+    /// Argon2id over the password, mixed with the digest of a keyfile afterwards.
+    /// Only a salted check value is stored, never the keyfile itself.
+    Argon2idKeyfileV1 {
+        salt: [u8; ARGON2_SALT_LEN],
+        m_cost: u32,
+        t_cost: u32,
+        p_cost: u32,
+        keyfile_salt: [u8; KEYFILE_SALT_LEN],
+        keyfile_check: [u8; KEYFILE_CHECK_LEN],
+    },
 }
 
 impl KdfParams {
-    pub fn try_new<R: TryRngCore>(rng: &mut R) -> Result<Self, IterateError> {
+    /// Creates parameters with a fresh salt and the cost calibrated for this machine.
+    /// Passing a keyfile makes it a required second factor.
+    pub fn try_new<R: TryRngCore>(
+        rng: &mut R,
+        keyfile: Option<&[u8]>,
+    ) -> Result<Self, IterateError> {
+        Self::try_with_cost(rng, calibrated_cost(), keyfile)
+    }
+
+    pub fn try_with_cost<R: TryRngCore>(
+        rng: &mut R,
+        cost: Argon2Cost,
+        keyfile: Option<&[u8]>,
+    ) -> Result<Self, IterateError> {
         let mut salt = [0u8; ARGON2_SALT_LEN];
         rng.try_fill_bytes(&mut salt)
             .map_err(|_| IterateError::SystemRngFailure)?;
 
-        Ok(Self::Argon2idV1 {
+        let Some(keyfile) = keyfile else {
+            return Ok(Self::Argon2idV1 {
+                salt,
+                m_cost: cost.m_cost,
+                t_cost: cost.t_cost,
+                p_cost: cost.p_cost,
+            });
+        };
+
+        let mut keyfile_salt = [0u8; KEYFILE_SALT_LEN];
+        rng.try_fill_bytes(&mut keyfile_salt)
+            .map_err(|_| IterateError::SystemRngFailure)?;
+
+        Ok(Self::Argon2idKeyfileV1 {
             salt,
-            m_cost: ARGON2_MEMORY_COST,
-            t_cost: ARGON2_TIME_COST,
-            p_cost: ARGON2_PARALLELISM,
+            m_cost: cost.m_cost,
+            t_cost: cost.t_cost,
+            p_cost: cost.p_cost,
+            keyfile_salt,
+            keyfile_check: keyfile_check(keyfile, &keyfile_salt)?,
         })
     }
 
+    pub fn try_new_recovery<R: TryRngCore>(rng: &mut R) -> Result<Self, IterateError> {
+        let mut salt = [0u8; RECOVERY_SALT_LEN];
+        rng.try_fill_bytes(&mut salt)
+            .map_err(|_| IterateError::SystemRngFailure)?;
+
+        Ok(Self::RecoveryKeyV1 { salt })
+    }
+
+    /// Returns true if the secret for these parameters is a user chosen password.
+    pub fn is_password_based(&self) -> bool {
+        matches!(
+            self,
+            KdfParams::Argon2idV1 { .. } | KdfParams::Argon2idKeyfileV1 { .. }
+        )
+    }
+
+    pub fn requires_keyfile(&self) -> bool {
+        matches!(self, KdfParams::Argon2idKeyfileV1 { .. })
+    }
+
+    /// Returns true if the parameters fall clearly behind the cost calibrated for this
+    /// machine, or behind the minimum while calibration is still running.
+    pub fn is_weaker_than_policy(&self) -> bool {
+        self.is_weaker_than(calibrated_cost_if_ready())
+    }
+
+    /// Calibration is a timing benchmark and varies between runs, so parameters close
+    /// to `target` are kept: memory may be one doubling step behind, and the total
+    /// work may fall short by up to `ARGON2_UPGRADE_THRESHOLD_PERCENT`.
+    fn is_weaker_than(&self, target: Argon2Cost) -> bool {
+        match self {
+            KdfParams::Argon2idV1 { m_cost, t_cost, .. }
+            | KdfParams::Argon2idKeyfileV1 { m_cost, t_cost, .. } => {
+                let (m_cost, t_cost) = (u64::from(*m_cost), u64::from(*t_cost));
+                let below_minimum = m_cost < u64::from(Argon2Cost::MINIMUM.m_cost)
+                    || t_cost < u64::from(Argon2Cost::MINIMUM.t_cost);
+                let memory_behind = m_cost * 2 < u64::from(target.m_cost);
+                let work_behind = m_cost * t_cost * 100
+                    < u64::from(target.m_cost)
+                        * u64::from(target.t_cost)
+                        * ARGON2_UPGRADE_THRESHOLD_PERCENT;
+
+                below_minimum || memory_behind || work_behind
+            }
+            KdfParams::RecoveryKeyV1 { .. } => false,
+        }
+    }
+
+    /// Creates parameters with a fresh salt that are at least as strong as both
+    /// `self` and `calibrated_cost_if_ready`, so memory is never raised past
+    /// `ARGON2_MAX_MEMORY_COST`. A keyfile requirement is carried over unchanged.
+    pub fn try_upgrade<R: TryRngCore>(&self, rng: &mut R) -> Result<Self, IterateError> {
+        let calibrated = calibrated_cost_if_ready();
+        let upgraded_cost = |m_cost: u32, t_cost: u32, p_cost: u32| Argon2Cost {
+            m_cost: m_cost.max(calibrated.m_cost),
+            t_cost: t_cost.max(calibrated.t_cost),
+            p_cost,
+        };
+
+        match self {
+            KdfParams::Argon2idV1 {
+                m_cost,
+                t_cost,
+                p_cost,
+                ..
+            } => Self::try_with_cost(rng, upgraded_cost(*m_cost, *t_cost, *p_cost), None),
+            KdfParams::RecoveryKeyV1 { .. } => Self::try_new_recovery(rng),
+            KdfParams::Argon2idKeyfileV1 {
+                m_cost,
+                t_cost,
+                p_cost,
+                keyfile_salt,
+                keyfile_check,
+                ..
+            } => {
+                let cost = upgraded_cost(*m_cost, *t_cost, *p_cost);
+                let mut salt = [0u8; ARGON2_SALT_LEN];
+                rng.try_fill_bytes(&mut salt)
+                    .map_err(|_| IterateError::SystemRngFailure)?;
+
+                Ok(Self::Argon2idKeyfileV1 {
+                    salt,
+                    m_cost: cost.m_cost,
+                    t_cost: cost.t_cost,
+                    p_cost: cost.p_cost,
+                    keyfile_salt: *keyfile_salt,
+                    keyfile_check: *keyfile_check,
+                })
+            }
+        }
+    }
+
     /// Deserializes the KDF parameters from a binary BLOB.
     pub fn from_bytes(bytes: &[u8]) -> Result<Self, IterateError> {
         postcard::from_bytes(bytes)
@@ -46,9 +180,12 @@ impl KdfParams {
             .map_err(|e| IterateError::PostCardSerializationFailed(e.to_string()))
     }
 
+    /// Derives the key encryption key. `keyfile` is the digest of the keyfile and
+    /// is ignored by parameters that do not require one.
     pub fn derive_key_encryption_key(
         &self,
         password: &[u8],
+        keyfile: Option<&[u8]>,
     ) -> Result<Zeroizing<[u8; 32]>, IterateError> {
         match self {
             KdfParams::Argon2idV1 {
@@ -56,16 +193,35 @@ impl KdfParams {
                 m_cost,
                 t_cost,
                 p_cost,
+            } => argon2id(password, salt, *m_cost, *t_cost, *p_cost),
+            KdfParams::RecoveryKeyV1 { salt } => {
+                let hk = IterateHkdf::new(Some(salt), password);
+                let mut key = Zeroizing::new([0u8; MASTER_KEY_LEN]);
+
+                hk.expand(b"recovery-key-wrapping", &mut *key)
+                    .map_err(|_| IterateError::HkdfExpansionFailed)?;
+
+                Ok(key)
+            }
+            KdfParams::Argon2idKeyfileV1 {
+                salt,
+                m_cost,
+                t_cost,
+                p_cost,
+                keyfile_salt,
+                keyfile_check: expected_check,
             } => {
-                let params = Params::new(*m_cost, *t_cost, *p_cost, Some(MASTER_KEY_LEN))
-                    .map_err(|_| IterateError::KeyDerivationFailed)?;
+                let keyfile = keyfile.ok_or(IterateError::KeyfileRequired)?;
+                if keyfile_check(keyfile, keyfile_salt)? != *expected_check {
+                    return Err(IterateError::KeyfileMismatch);
+                }
 
-                let argon = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+                let password_key = argon2id(password, salt, *m_cost, *t_cost, *p_cost)?;
+                let hk = IterateHkdf::new(Some(keyfile), password_key.as_ref());
                 let mut key = Zeroizing::new([0u8; MASTER_KEY_LEN]);
 
-                argon
-                    .hash_password_into(password, salt, &mut *key)
-                    .map_err(|_| IterateError::KeyDerivationFailed)?;
+                hk.expand(b"keyfile-mixing", &mut *key)
+                    .map_err(|_| IterateError::HkdfExpansionFailed)?;
 
                 Ok(key)
             }
@@ -73,20 +229,122 @@ impl KdfParams {
     }
 }
 
+fn argon2id(
+    password: &[u8],
+    salt: &[u8],
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+) -> Result<Zeroizing<[u8; 32]>, IterateError> {
+    let cost = Argon2Cost {
+        m_cost,
+        t_cost,
+        p_cost,
+    };
+    let mut key = Zeroizing::new([0u8; MASTER_KEY_LEN]);
+    hash_argon2id(&cost, password, salt, &mut *key)?;
+
+    Ok(key)
+}
+
+/// Runs Argon2id in memory that is reserved up front. Calibrated parameters can ask
+/// for up to 1 GiB, and a machine that cannot provide it gets `InsufficientKdfMemory`
+/// instead of an aborted process.
+pub(crate) fn hash_argon2id(
+    cost: &Argon2Cost,
+    password: &[u8],
+    salt: &[u8],
+    out: &mut [u8],
+) -> Result<(), IterateError> {
+    let params = Params::new(cost.m_cost, cost.t_cost, cost.p_cost, Some(out.len()))
+        .map_err(|_| IterateError::KeyDerivationFailed)?;
+
+    let mut memory = Vec::new();
+    memory
+        .try_reserve_exact(params.block_count())
+        .map_err(|_| IterateError::InsufficientKdfMemory)?;
+    memory.resize(params.block_count(), Block::default());
+
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into_with_memory(password, salt, out, &mut memory)
+        .map_err(|_| IterateError::KeyDerivationFailed)
+}
+
+/// Salted fingerprint that tells a wrong keyfile apart from a wrong password.
+fn keyfile_check(
+    keyfile: &[u8],
+    keyfile_salt: &[u8; KEYFILE_SALT_LEN],
+) -> Result<[u8; KEYFILE_CHECK_LEN], IterateError> {
+    let hk = IterateHkdf::new(Some(keyfile_salt), keyfile);
+    let mut check = [0u8; KEYFILE_CHECK_LEN];
+
+    hk.expand(b"keyfile-check", &mut check)
+        .map_err(|_| IterateError::HkdfExpansionFailed)?;
+
+    Ok(check)
+}
+
 type IterateHkdf = Hkdf<Sha512>;
 pub fn derive_service_keys(master_key: &Zeroizing<[u8; 32]>) -> Result<ServiceKeys, IterateError> {
     let hk = IterateHkdf::new(Some(b"iterate-journal-v1"), master_key.as_ref());
 
     let mut content_key = [0u8; MASTER_KEY_LEN];
     let mut meta_key = [0u8; MASTER_KEY_LEN];
+    let mut record_metadata_key = [0u8; MASTER_KEY_LEN];
+    let mut chain_key = [0u8; MASTER_KEY_LEN];
+    let mut topic_key = [0u8; MASTER_KEY_LEN];
+    let mut blind_index_key = [0u8; MASTER_KEY_LEN];
 
     hk.expand(b"content-encryption-key", &mut content_key)
         .map_err(|_| IterateError::HkdfExpansionFailed)?;
     hk.expand(b"meta-verification-key", &mut meta_key)
         .map_err(|_| IterateError::HkdfExpansionFailed)?;
+    hk.expand(b"record-metadata-encryption-key", &mut record_metadata_key)
+        .map_err(|_| IterateError::HkdfExpansionFailed)?;
+    hk.expand(b"record-chain-key", &mut chain_key)
+        .map_err(|_| IterateError::HkdfExpansionFailed)?;
+    hk.expand(b"topic-encryption-key", &mut topic_key)
+        .map_err(|_| IterateError::HkdfExpansionFailed)?;
+    hk.expand(b"topic-blind-index-key", &mut blind_index_key)
+        .map_err(|_| IterateError::HkdfExpansionFailed)?;
 
     Ok(ServiceKeys {
         content: Zeroizing::new(content_key),
         meta: Zeroizing::new(meta_key),
+        record_metadata: Zeroizing::new(record_metadata_key),
+        chain: Zeroizing::new(chain_key),
+        topic: Zeroizing::new(topic_key),
+        blind_index: Zeroizing::new(blind_index_key),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn argon2_params(m_cost: u32, t_cost: u32) -> KdfParams {
+        KdfParams::Argon2idV1 {
+            salt: [0u8; ARGON2_SALT_LEN],
+            m_cost,
+            t_cost,
+            p_cost: Argon2Cost::MINIMUM.p_cost,
+        }
+    }
+
+    #[test]
+    fn only_costs_clearly_behind_the_calibration_are_weaker() {
+        let target = Argon2Cost {
+            m_cost: 512 * 1024,
+            t_cost: 4,
+            ..Argon2Cost::MINIMUM
+        };
+
+        assert!(!argon2_params(512 * 1024, 4).is_weaker_than(target));
+        assert!(!argon2_params(512 * 1024, 3).is_weaker_than(target));
+        assert!(!argon2_params(256 * 1024, 8).is_weaker_than(target));
+        assert!(argon2_params(256 * 1024, 4).is_weaker_than(target));
+        assert!(argon2_params(128 * 1024, 16).is_weaker_than(target));
+        assert!(argon2_params(Argon2Cost::MINIMUM.m_cost, 3).is_weaker_than(target));
+        assert!(argon2_params(512 * 1024, 2).is_weaker_than(target));
+    }
+}
@@ -0,0 +1,29 @@
+use crate::error::IterateError;
+use sha2::{Digest, Sha512};
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::path::Path;
+use zeroize::Zeroizing;
+
+/// Hashes a keyfile in chunks, so any file (e.g. a photo) can serve as keyfile.
+/// The digest is what gets mixed into the key derivation.
+pub fn digest_keyfile(path: &Path) -> Result<Zeroizing<[u8; 64]>, IterateError> {
+    let mut file = File::open(path).map_err(|e| match e.kind() {
+        ErrorKind::NotFound => IterateError::KeyfileNotFound,
+        _ => IterateError::Io(e),
+    })?;
+
+    let mut hasher = Sha512::new();
+    let mut buffer = Zeroizing::new([0u8; 8192]);
+    loop {
+        let read = file.read(buffer.as_mut())?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+
+    let mut digest = Zeroizing::new([0u8; 64]);
+    digest.copy_from_slice(&hasher.finalize());
+    Ok(digest)
+}
@@ -0,0 +1,41 @@
+use crate::crypto::constants::MAC_LEN;
+use crate::error::IterateError;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use zeroize::Zeroizing;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// HMAC-SHA256 over `data`. The domain separates MACs made with the same key for different purposes.
+pub fn compute_mac(
+    key: &Zeroizing<[u8; 32]>,
+    domain: &[u8],
+    data: &[u8],
+) -> Result<[u8; MAC_LEN], IterateError> {
+    let mut mac = keyed(key, domain)?;
+    mac.update(data);
+
+    Ok(mac.finalize().into_bytes().into())
+}
+
+/// Checks a MAC in constant time.
+pub fn verify_mac(
+    key: &Zeroizing<[u8; 32]>,
+    domain: &[u8],
+    data: &[u8],
+    expected: &[u8],
+) -> Result<bool, IterateError> {
+    let mut mac = keyed(key, domain)?;
+    mac.update(data);
+
+    Ok(mac.verify_slice(expected).is_ok())
+}
+
+fn keyed(key: &Zeroizing<[u8; 32]>, domain: &[u8]) -> Result<HmacSha256, IterateError> {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key.as_ref())
+        .map_err(|e| IterateError::Internal(e.to_string()))?;
+    mac.update(&(domain.len() as u64).to_le_bytes());
+    mac.update(domain);
+
+    Ok(mac)
+}
@@ -1,5 +1,12 @@
 pub mod aead;
+pub mod calibration;
+pub mod compression;
 pub(crate) mod constants;
 pub mod cryptoenvelope;
+pub mod datakey;
 pub mod kdf;
+pub mod keyfile;
+pub mod mac;
+pub mod padding;
+pub mod recovery;
 pub mod servicekeys;
@@ -0,0 +1,130 @@
+use crate::crypto::constants::{PADDING_MARKER, PADDING_MIN_BUCKET};
+use crate::error::IterateError;
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+/// How plaintext is padded before encryption, so the ciphertext length only
+/// reveals a size bucket instead of the exact length.
+///
+/// Padded plaintext ends with `PADDING_MARKER` followed by zero bytes.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[repr(u8)]
+pub enum Padding {
+    None = 0,
+    /// Rounds up to the next power of two. Hides the most, at up to twice the size.
+    PowerOfTwo = 1,
+    /// PADMÉ: leaks O(log log n) bits of the length with at most 12% overhead.
+    Padme = 2,
+}
+
+impl Padding {
+    pub fn from_id(id: i64) -> Option<Self> {
+        match id {
+            0 => Some(Padding::None),
+            1 => Some(Padding::PowerOfTwo),
+            2 => Some(Padding::Padme),
+            _ => None,
+        }
+    }
+
+    pub fn pad(&self, plaintext: &[u8]) -> Zeroizing<Vec<u8>> {
+        if *self == Padding::None {
+            return Zeroizing::new(plaintext.to_vec());
+        }
+
+        let marked_len = plaintext.len() + 1;
+        let bucket = match self {
+            Padding::None => marked_len,
+            Padding::PowerOfTwo => marked_len.max(PADDING_MIN_BUCKET).next_power_of_two(),
+            Padding::Padme => padme(marked_len.max(PADDING_MIN_BUCKET)),
+        };
+
+        let mut padded = Zeroizing::new(Vec::with_capacity(bucket));
+        padded.extend_from_slice(plaintext);
+        padded.push(PADDING_MARKER);
+        padded.resize(bucket, 0);
+        padded
+    }
+
+    /// Strips the padding added by `pad`. Only call this on authenticated plaintext.
+    pub fn unpad(&self, mut padded: Vec<u8>) -> Result<Vec<u8>, IterateError> {
+        if *self == Padding::None {
+            return Ok(padded);
+        }
+
+        let marker = padded
+            .iter()
+            .rposition(|byte| *byte != 0)
+            .filter(|&position| padded[position] == PADDING_MARKER)
+            .ok_or_else(|| IterateError::DecryptionFailed("Invalid padding".into()))?;
+
+        padded.truncate(marker);
+        Ok(padded)
+    }
+}
+
+/// Rounds `len` up to its PADMÉ bucket by clearing the lowest `e - s` bits,
+/// where `e = floor(log2 len)` and `s = floor(log2 e) + 1`.
+fn padme(len: usize) -> usize {
+    if len < 2 {
+        return len;
+    }
+
+    let exponent = len.ilog2();
+    let significant_bits = exponent.ilog2() + 1;
+    let mask = (1usize << (exponent - significant_bits)) - 1;
+
+    (len + mask) & !mask
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PADDINGS: [Padding; 3] = [Padding::None, Padding::PowerOfTwo, Padding::Padme];
+
+    #[test]
+    fn unpad_restores_what_pad_added() {
+        for padding in PADDINGS {
+            for plaintext in [
+                &b""[..],
+                b"a",
+                b"ends in zeros\0\0",
+                &[0x80; 64],
+                &[7; 1000],
+            ] {
+                let padded = padding.pad(plaintext);
+                assert_eq!(
+                    padding.unpad(padded.to_vec()).unwrap(),
+                    plaintext,
+                    "{:?}",
+                    padding
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn pads_to_the_bucket_of_the_policy() {
+        assert_eq!(Padding::PowerOfTwo.pad(b"").len(), PADDING_MIN_BUCKET);
+        assert_eq!(Padding::PowerOfTwo.pad(&[1; 64]).len(), 128);
+        assert_eq!(Padding::Padme.pad(&[1; 1000]).len(), padme(1001));
+        assert!(padme(1001) >= 1001 && padme(1001) <= 1001 * 112 / 100);
+    }
+
+    #[test]
+    fn rejects_malformed_padding() {
+        for padded in [
+            vec![],
+            vec![0; 64],
+            vec![1, 2, 3, 0, 0],
+            vec![PADDING_MARKER, 1],
+        ] {
+            assert!(matches!(
+                Padding::PowerOfTwo.unpad(padded),
+                Err(IterateError::DecryptionFailed(_))
+            ));
+        }
+    }
+}
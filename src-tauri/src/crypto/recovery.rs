@@ -0,0 +1,49 @@
+use crate::crypto::constants::RECOVERY_KEY_LEN;
+use crate::error::IterateError;
+use bip39::{Language, Mnemonic};
+use rand::TryRngCore;
+use zeroize::Zeroizing;
+
+/// Random secret that can unlock a journal in place of the password.
+/// It is shown to the user exactly once, encoded as a BIP39 word list.
+pub struct RecoveryKey {
+    entropy: Zeroizing<[u8; RECOVERY_KEY_LEN]>,
+}
+
+impl RecoveryKey {
+    pub fn generate<R: TryRngCore>(rng: &mut R) -> Result<Self, IterateError> {
+        let mut entropy = Zeroizing::new([0u8; RECOVERY_KEY_LEN]);
+        rng.try_fill_bytes(entropy.as_mut())
+            .map_err(|_| IterateError::SystemRngFailure)?;
+
+        Ok(Self { entropy })
+    }
+
+    /// Parses a phrase typed by the user. Case and surrounding whitespace are ignored.
+    pub fn from_phrase(phrase: &str) -> Result<Self, IterateError> {
+        let normalized = Zeroizing::new(phrase.trim().to_lowercase());
+        let mnemonic = Mnemonic::parse_in_normalized(Language::English, &normalized)
+            .map_err(|_| IterateError::InvalidRecoveryPhrase)?;
+
+        let (bytes, len) = mnemonic.to_entropy_array();
+        let bytes = Zeroizing::new(bytes);
+        if len != RECOVERY_KEY_LEN {
+            return Err(IterateError::InvalidRecoveryPhrase);
+        }
+
+        let mut entropy = Zeroizing::new([0u8; RECOVERY_KEY_LEN]);
+        entropy.copy_from_slice(&bytes[..RECOVERY_KEY_LEN]);
+        Ok(Self { entropy })
+    }
+
+    pub fn to_phrase(&self) -> Result<Zeroizing<String>, IterateError> {
+        let mnemonic = Mnemonic::from_entropy_in(Language::English, self.entropy.as_ref())
+            .map_err(|e| IterateError::Internal(e.to_string()))?;
+
+        Ok(Zeroizing::new(mnemonic.to_string()))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.entropy.as_ref()
+    }
+}
@@ -3,4 +3,8 @@ use zeroize::Zeroizing;
 pub struct ServiceKeys {
     pub content: Zeroizing<[u8; 32]>,
     pub meta: Zeroizing<[u8; 32]>,
+    pub record_metadata: Zeroizing<[u8; 32]>,
+    pub chain: Zeroizing<[u8; 32]>,
+    pub topic: Zeroizing<[u8; 32]>,
+    pub blind_index: Zeroizing<[u8; 32]>,
 }
@@ -3,8 +3,6 @@
 -- Initial schema for Iterate
 -- ================================
 
-BEGIN IMMEDIATE TRANSACTION;
-
 -- ----------------
 -- Metadata
 -- ----------------
@@ -78,19 +76,12 @@ CREATE TABLE record_topic (
 );
 
 -- ----------------
--- Encrypted Integrity Table
+-- Key Store
 -- ----------------
+-- Exactly one row allowed; 002_key_slots.sql turns it into one row per unlock secret.
 CREATE TABLE key_store (
     id INTEGER PRIMARY KEY CHECK (id = 1),
-    kdf_strategy    INTEGER NOT NULL, 
-    kdf_salt        BLOB NOT NULL,
-    kdf_params      TEXT NOT NULL,    
-    wrapped_key     BLOB NOT NULL,    
+    kdf_params      BLOB NOT NULL,    -- Postcard Serialized KdfParams
+    wrapped_key     BLOB NOT NULL,    -- CryptoEnvelope(MasterKey)
     created_at_utc  INTEGER NOT NULL
 );
-
--- Exactly one row allowed
-CREATE UNIQUE INDEX idx_integrity_singleton
-ON integrity_check(id);
-
-COMMIT;
@@ -0,0 +1,20 @@
+-- ================================
+-- 002_key_slots.sql
+-- key_store holds one row per unlock secret (password, recovery key)
+-- ================================
+
+ALTER TABLE key_store RENAME TO key_store_v1;
+
+CREATE TABLE key_store (
+    slot_id         INTEGER PRIMARY KEY AUTOINCREMENT,
+    label           TEXT NOT NULL,
+    kdf_params      BLOB NOT NULL,    -- Postcard Serialized KdfParams
+    wrapped_key     BLOB NOT NULL,    -- CryptoEnvelope(MasterKey)
+    created_at_utc  INTEGER NOT NULL
+);
+
+INSERT INTO key_store (label, kdf_params, wrapped_key, created_at_utc)
+SELECT 'Password', kdf_params, wrapped_key, created_at_utc
+FROM key_store_v1;
+
+DROP TABLE key_store_v1;
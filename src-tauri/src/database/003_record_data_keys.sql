@@ -0,0 +1,7 @@
+-- ================================
+-- 003_record_data_keys.sql
+-- Every record gets its own data key, wrapped by the content key.
+-- NULL marks records that were encrypted with the content key directly.
+-- ================================
+
+ALTER TABLE record ADD COLUMN wrapped_data_key BLOB;
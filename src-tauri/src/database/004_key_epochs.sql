@@ -0,0 +1,9 @@
+-- ================================
+-- 004_key_epochs.sql
+-- Tracks which master key generation encrypted a record,
+-- so a content key rotation can resume after a crash.
+-- ================================
+
+ALTER TABLE record ADD COLUMN key_epoch INTEGER NOT NULL DEFAULT 0;
+
+INSERT OR IGNORE INTO metadata (key, value) VALUES ('key_epoch', '0');
@@ -0,0 +1,10 @@
+-- ================================
+-- 005_record_metadata_mac.sql
+-- HMAC (meta key) over the plaintext columns of a record.
+-- Existing rows are authenticated after the next unlock. Only this marker lets
+-- a row without a MAC through, so it is set here rather than by the app.
+-- ================================
+
+ALTER TABLE record ADD COLUMN metadata_mac BLOB;
+
+INSERT OR IGNORE INTO metadata (key, value) VALUES ('record_macs_pending', '1');
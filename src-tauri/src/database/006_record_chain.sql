@@ -0,0 +1,16 @@
+-- ================================
+-- 006_record_chain.sql
+-- Append-only MAC chain over record writes, keyed with the chain key.
+-- The next unlock starts the chain for existing records. Only this marker lets
+-- a journal without a chain through, so it is set here rather than by the app.
+-- ================================
+
+CREATE TABLE IF NOT EXISTS record_chain (
+    sequence INTEGER PRIMARY KEY,
+    record_id BLOB NOT NULL,
+    operation INTEGER NOT NULL,
+    metadata_mac BLOB,
+    link_mac BLOB NOT NULL
+);
+
+INSERT OR IGNORE INTO metadata (key, value) VALUES ('record_chain_pending', '1');
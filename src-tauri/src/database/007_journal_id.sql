@@ -0,0 +1,7 @@
+-- ================================
+-- 007_journal_id.sql
+-- Random per-journal identifier, bound into the AAD of record envelopes
+-- so ciphertexts cannot be moved between journals.
+-- ================================
+
+INSERT OR IGNORE INTO metadata (key, value) VALUES ('journal_id', lower(hex(randomblob(16))));
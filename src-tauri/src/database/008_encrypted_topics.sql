@@ -0,0 +1,38 @@
+-- ================================
+-- 008_encrypted_topics.sql
+-- Topic names, notes and prompt texts become CryptoEnvelope blobs.
+-- A keyed blind index replaces the UNIQUE constraint on topic names.
+-- Existing rows wait in legacy_* tables until the next unlock encrypts them.
+-- ================================
+
+CREATE TABLE legacy_topic AS SELECT topic_id, name, note, created_at_utc FROM topic;
+CREATE TABLE legacy_prompt AS SELECT prompt_id, topic_id, prompt_text, is_default FROM prompt;
+-- Foreign keys are off while migrating, so dropping topic leaves the tags alone.
+-- They wait aside as well, because their topics only come back at the next unlock.
+CREATE TABLE legacy_record_topic AS SELECT record_id, topic_id FROM record_topic;
+DELETE FROM record_topic;
+
+DROP INDEX IF EXISTS idx_prompt_default;
+DROP TABLE prompt;
+DROP TABLE topic;
+
+CREATE TABLE topic (
+    topic_id BLOB PRIMARY KEY, -- UUIDv7 (16 bytes)
+    name BLOB NOT NULL,        -- CryptoEnvelope
+    name_index BLOB NOT NULL UNIQUE, -- HMAC over the normalized name
+    note BLOB,                 -- CryptoEnvelope
+    created_at_utc INTEGER NOT NULL
+);
+
+CREATE TABLE prompt (
+    prompt_id BLOB PRIMARY KEY,
+    topic_id BLOB NOT NULL,
+    prompt_text BLOB NOT NULL, -- CryptoEnvelope
+    is_default INTEGER NOT NULL CHECK (is_default IN (0,1)),
+    FOREIGN KEY (topic_id) REFERENCES topic(topic_id) ON DELETE CASCADE
+);
+
+-- Enforce only one default prompt per topic
+CREATE UNIQUE INDEX idx_prompt_default
+ON prompt(topic_id)
+WHERE is_default = 1;
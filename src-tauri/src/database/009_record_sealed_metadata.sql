@@ -0,0 +1,7 @@
+-- ================================
+-- 009_record_sealed_metadata.sql
+-- Optional CryptoEnvelope (record metadata key) with the exact timestamps,
+-- sentiment and flags of a record. Only written in privacy mode.
+-- ================================
+
+ALTER TABLE record ADD COLUMN sealed_metadata BLOB;
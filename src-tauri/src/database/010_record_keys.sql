@@ -0,0 +1,17 @@
+-- ================================
+-- 010_record_keys.sql
+-- Wrapped data keys move out of the record rows, so deleting a record destroys
+-- its key in one place. Its ciphertext left in freed pages or the WAL cannot
+-- be opened without it.
+-- ================================
+
+CREATE TABLE record_key (
+    record_id BLOB PRIMARY KEY,
+    wrapped_data_key BLOB NOT NULL, -- CryptoEnvelope(DataKey)
+    FOREIGN KEY (record_id) REFERENCES record(record_id) ON DELETE CASCADE
+);
+
+INSERT INTO record_key (record_id, wrapped_data_key)
+SELECT record_id, wrapped_data_key FROM record WHERE wrapped_data_key IS NOT NULL;
+
+ALTER TABLE record DROP COLUMN wrapped_data_key;
@@ -0,0 +1,26 @@
+-- ================================
+-- 011_repair_key_store.sql
+-- The original 001_init.sql could not run as written. Journals created from
+-- hand-repaired copies of it may keep its leftovers: TEXT kdf_params, the
+-- NOT NULL kdf_strategy and kdf_salt columns, and idx_integrity_singleton.
+-- key_store is rebuilt with the slot layout of 002_key_slots.sql, which is
+-- a no-op for a conforming one, and the stray index is dropped.
+-- ================================
+
+DROP INDEX IF EXISTS idx_integrity_singleton;
+
+CREATE TABLE key_store_repaired (
+    slot_id         INTEGER PRIMARY KEY AUTOINCREMENT,
+    label           TEXT NOT NULL,
+    kdf_params      BLOB NOT NULL,    -- Postcard Serialized KdfParams
+    wrapped_key     BLOB NOT NULL,    -- CryptoEnvelope(MasterKey)
+    created_at_utc  INTEGER NOT NULL
+);
+
+INSERT INTO key_store_repaired (slot_id, label, kdf_params, wrapped_key, created_at_utc)
+SELECT slot_id, label, CAST(kdf_params AS BLOB), CAST(wrapped_key AS BLOB), created_at_utc
+FROM key_store;
+
+DROP TABLE key_store;
+
+ALTER TABLE key_store_repaired RENAME TO key_store;
@@ -0,0 +1,238 @@
+use crate::crypto::constants::MAC_LEN;
+use crate::crypto::mac::{compute_mac, verify_mac};
+use crate::database::MetadataRepository;
+use crate::error::IterateError;
+use rusqlite::{Connection, Row, params};
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+const CHAIN_LINK_DOMAIN: &[u8] = b"record-chain-v1";
+const CHAIN_STARTED_DOMAIN: &[u8] = b"record-chain-started-v1";
+const CHAIN_HEAD: &str = "record_chain_head";
+/// MAC over nothing, keyed with the chain key, written when the chain is started.
+const CHAIN_STARTED: &str = "record_chain_started";
+/// Set by migration 006 until the chain is started for the records that predate it.
+const CHAIN_PENDING: &str = "record_chain_pending";
+
+/// The predecessor of the first link.
+pub const GENESIS_LINK: [u8; MAC_LEN] = [0u8; MAC_LEN];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ChainOperation {
+    /// The record was inserted or changed; the link carries its new metadata MAC.
+    Write = 0,
+    /// The record was removed from the table.
+    Delete = 1,
+}
+
+impl ChainOperation {
+    fn from_i64(value: i64) -> Option<Self> {
+        match value {
+            0 => Some(Self::Write),
+            1 => Some(Self::Delete),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChainState {
+    /// Migration 006 added the chain, which has not been started for the existing records.
+    Pending,
+    Started,
+    /// The started marker is forged, or gone while links or a head remain or no
+    /// migration left the chain pending.
+    Missing,
+}
+
+/// A record id with the metadata MAC stored next to it.
+pub type RecordMac = (Uuid, Option<Vec<u8>>);
+
+pub struct ChainLink {
+    pub sequence: i64,
+    pub record_id: Uuid,
+    /// `None` if the stored operation is unknown, which verification treats as a break.
+    pub operation: Option<ChainOperation>,
+    pub metadata_mac: Option<Vec<u8>>,
+    pub link_mac: Vec<u8>,
+}
+
+/// Append-only log of record writes. Every link is a MAC over its predecessor,
+/// keyed with `ServiceKeys::chain`, and the newest one is kept in `metadata`.
+/// Removing or reinserting a row, or cutting links off the log, breaks the chain.
+pub struct RecordChain<'a> {
+    conn: &'a Connection,
+    chain_key: &'a Zeroizing<[u8; 32]>,
+}
+
+impl<'a> RecordChain<'a> {
+    pub fn new(conn: &'a Connection, chain_key: &'a Zeroizing<[u8; 32]>) -> Self {
+        Self { conn, chain_key }
+    }
+
+    pub fn head(&self) -> Result<Option<Vec<u8>>, IterateError> {
+        MetadataRepository::new(self.conn).get_blob(CHAIN_HEAD)
+    }
+
+    /// Tells a chain that was never started apart from one that was removed.
+    pub fn state(&self) -> Result<ChainState, IterateError> {
+        let metadata = MetadataRepository::new(self.conn);
+        if let Some(marker) = metadata.get_blob(CHAIN_STARTED)? {
+            return Ok(
+                if verify_mac(self.chain_key, CHAIN_STARTED_DOMAIN, &[], &marker)? {
+                    ChainState::Started
+                } else {
+                    ChainState::Missing
+                },
+            );
+        }
+
+        let has_links: bool =
+            self.conn
+                .query_row("SELECT EXISTS(SELECT 1 FROM record_chain)", [], |row| {
+                    row.get(0)
+                })?;
+        if metadata.get(CHAIN_PENDING)?.is_some() && !has_links && self.head()?.is_none() {
+            return Ok(ChainState::Pending);
+        }
+        Ok(ChainState::Missing)
+    }
+
+    pub fn append(
+        &self,
+        record_id: &Uuid,
+        operation: ChainOperation,
+        metadata_mac: Option<&[u8]>,
+    ) -> Result<(), IterateError> {
+        let previous = self.head()?.unwrap_or_else(|| GENESIS_LINK.to_vec());
+        let sequence: i64 = self
+            .conn
+            .prepare_cached("SELECT COALESCE(MAX(sequence), 0) + 1 FROM record_chain")?
+            .query_row([], |row| row.get(0))?;
+        let link_mac = self.link_mac(&previous, sequence, record_id, operation, metadata_mac)?;
+
+        self.conn
+            .prepare_cached(
+                "INSERT INTO record_chain (sequence, record_id, operation, metadata_mac, link_mac)
+                 VALUES (?, ?, ?, ?, ?)",
+            )?
+            .execute(params![
+                sequence,
+                record_id.as_bytes(),
+                operation as u8,
+                metadata_mac,
+                link_mac
+            ])?;
+        MetadataRepository::new(self.conn).set_blob(CHAIN_HEAD, &link_mac)
+    }
+
+    /// All links, oldest first.
+    pub fn links(&self) -> Result<Vec<ChainLink>, IterateError> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT * FROM record_chain ORDER BY sequence")?;
+        let links = stmt
+            .query_map([], map_link)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(links)
+    }
+
+    /// Returns true if `link` follows from the link MAC `previous`.
+    pub fn verify_link(&self, previous: &[u8], link: &ChainLink) -> Result<bool, IterateError> {
+        let Some(operation) = link.operation else {
+            return Ok(false);
+        };
+
+        verify_mac(
+            self.chain_key,
+            CHAIN_LINK_DOMAIN,
+            &link_bytes(
+                previous,
+                link.sequence,
+                &link.record_id,
+                operation,
+                link.metadata_mac.as_deref(),
+            )?,
+            &link.link_mac,
+        )
+    }
+
+    /// The id and metadata MAC of every row in the `record` table, which the chain has to account for.
+    pub fn record_macs(&self) -> Result<Vec<RecordMac>, IterateError> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT record_id, metadata_mac FROM record ORDER BY record_id")?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((
+                    Uuid::from_slice(&row.get::<_, Vec<u8>>(0)?).unwrap_or_default(),
+                    row.get(1)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(rows)
+    }
+
+    /// Drops the log and starts a new one with a single write link for every current record.
+    /// Used for journals that predate the chain and after the chain key changed.
+    pub fn restart(&self) -> Result<usize, IterateError> {
+        let records = self.record_macs()?;
+        let metadata = MetadataRepository::new(self.conn);
+
+        self.conn.execute("DELETE FROM record_chain", [])?;
+        metadata.set_blob(CHAIN_HEAD, &GENESIS_LINK)?;
+        metadata.set_blob(
+            CHAIN_STARTED,
+            &compute_mac(self.chain_key, CHAIN_STARTED_DOMAIN, &[])?,
+        )?;
+        metadata.delete(CHAIN_PENDING)?;
+        for (record_id, metadata_mac) in &records {
+            self.append(record_id, ChainOperation::Write, metadata_mac.as_deref())?;
+        }
+
+        Ok(records.len())
+    }
+
+    fn link_mac(
+        &self,
+        previous: &[u8],
+        sequence: i64,
+        record_id: &Uuid,
+        operation: ChainOperation,
+        metadata_mac: Option<&[u8]>,
+    ) -> Result<[u8; MAC_LEN], IterateError> {
+        compute_mac(
+            self.chain_key,
+            CHAIN_LINK_DOMAIN,
+            &link_bytes(previous, sequence, record_id, operation, metadata_mac)?,
+        )
+    }
+}
+
+fn link_bytes(
+    previous: &[u8],
+    sequence: i64,
+    record_id: &Uuid,
+    operation: ChainOperation,
+    metadata_mac: Option<&[u8]>,
+) -> Result<Vec<u8>, IterateError> {
+    postcard::to_stdvec(&(
+        previous,
+        sequence,
+        record_id.as_bytes(),
+        operation as u8,
+        metadata_mac,
+    ))
+    .map_err(|e| IterateError::PostCardSerializationFailed(e.to_string()))
+}
+
+fn map_link(row: &Row) -> rusqlite::Result<ChainLink> {
+    Ok(ChainLink {
+        sequence: row.get("sequence")?,
+        record_id: Uuid::from_slice(&row.get::<_, Vec<u8>>("record_id")?).unwrap_or_default(),
+        operation: ChainOperation::from_i64(row.get("operation")?),
+        metadata_mac: row.get("metadata_mac")?,
+        link_mac: row.get("link_mac")?,
+    })
+}
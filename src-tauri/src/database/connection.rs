@@ -1,20 +1,47 @@
 use rusqlite::{Connection, Result};
 
-use crate::database::migrations::run_migrations;
+use crate::database::migrations::{SchemaAccess, run_migrations};
 use crate::error::IterateError;
 
+/// Statements kept prepared per connection. Covers the queries of a save with room to spare.
+const STATEMENT_CACHE_CAPACITY: usize = 64;
+
+/// Opens the journal and brings its schema up to date. The full integrity check is
+/// left to `check_integrity`, since an unlocked session keeps its connection open.
+/// A journal from a newer version is opened with `query_only`, see `is_read_only`.
 pub fn open_database(path: std::path::PathBuf) -> Result<Connection, IterateError> {
+    let access = run_migrations(&path)?;
     let conn = Connection::open(path)?;
-    run_migrations(&conn)?;
+    apply_pragmas(&conn)?;
+    if access == SchemaAccess::ReadOnly {
+        conn.pragma_update(None, "query_only", true)?;
+    }
+    conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
+
+    Ok(conn)
+}
+
+/// True if the connection rejects writes because the journal has a newer schema.
+pub fn is_read_only(conn: &Connection) -> Result<bool, IterateError> {
+    Ok(conn.query_row("PRAGMA query_only;", [], |row| row.get(0))?)
+}
+
+/// Fails with `ReadOnlyJournal` before a command starts writing to such a journal.
+pub fn ensure_writable(conn: &Connection) -> Result<(), IterateError> {
+    if is_read_only(conn)? {
+        return Err(IterateError::ReadOnlyJournal);
+    }
+    Ok(())
+}
+
+/// Runs `PRAGMA integrity_check` over the whole file. Slow on large journals.
+pub fn check_integrity(conn: &Connection) -> Result<(), IterateError> {
     let ok: String = conn.query_row("PRAGMA integrity_check;", [], |row| row.get(0))?;
 
     if ok != "ok" {
         return Err(IterateError::DatabaseIntegrity);
     }
-
-    apply_pragmas(&conn)?;
-
-    Ok(conn)
+    Ok(())
 }
 
 fn apply_pragmas(conn: &Connection) -> Result<()> {
@@ -31,3 +58,49 @@ fn apply_pragmas(conn: &Connection) -> Result<()> {
     )?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::RecordRepository;
+    use crate::database::migrations::SCHEMA_VERSION;
+    use crate::database::testing::{TempJournal, service_keys};
+    use rand::rngs::OsRng;
+    use rusqlite::params;
+    use uuid::Uuid;
+
+    #[test]
+    fn rejects_writes_to_a_journal_from_a_newer_version() {
+        let journal = TempJournal::in_temp_dir();
+        let conn = open_database(journal.0.clone()).unwrap();
+        assert!(ensure_writable(&conn).is_ok());
+        // Still declares this version as a capable reader.
+        conn.execute(
+            "UPDATE metadata SET value = ? WHERE key = 'schema_version'",
+            params![(SCHEMA_VERSION + 1).to_string()],
+        )
+        .unwrap();
+        drop(conn);
+
+        let conn = open_database(journal.0.clone()).expect("a newer journal opens");
+        assert!(is_read_only(&conn).unwrap());
+        assert!(matches!(
+            ensure_writable(&conn),
+            Err(IterateError::ReadOnlyJournal)
+        ));
+
+        let keys = service_keys();
+        let inserted = RecordRepository::new(&conn, &keys).insert(
+            &mut OsRng,
+            &Uuid::now_v7(),
+            b"content",
+            b"key",
+            None,
+        );
+        assert!(inserted.is_err());
+        let records: i64 = conn
+            .query_row("SELECT COUNT(*) FROM record", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(records, 0);
+    }
+}
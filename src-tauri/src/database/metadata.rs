@@ -0,0 +1,66 @@
+use crate::error::IterateError;
+use rusqlite::{Connection, OptionalExtension, params};
+
+/// Key/value access to the `metadata` table.
+pub struct MetadataRepository<'a> {
+    conn: &'a Connection,
+}
+
+impl<'a> MetadataRepository<'a> {
+    pub fn new(conn: &'a Connection) -> Self {
+        Self { conn }
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<String>, IterateError> {
+        Ok(self
+            .conn
+            .prepare_cached("SELECT value FROM metadata WHERE key = ?")?
+            .query_row(params![key], |row| row.get(0))
+            .optional()?)
+    }
+
+    pub fn get_blob(&self, key: &str) -> Result<Option<Vec<u8>>, IterateError> {
+        Ok(self
+            .conn
+            .prepare_cached("SELECT value FROM metadata WHERE key = ?")?
+            .query_row(params![key], |row| row.get(0))
+            .optional()?)
+    }
+
+    pub fn get_i64(&self, key: &str) -> Result<Option<i64>, IterateError> {
+        match self.get(key)? {
+            Some(value) => value
+                .parse()
+                .map(Some)
+                .map_err(|_| IterateError::Internal(format!("metadata '{}' is not a number", key))),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set(&self, key: &str, value: &str) -> Result<(), IterateError> {
+        self.conn
+            .prepare_cached(
+                "INSERT INTO metadata (key, value) VALUES (?, ?)
+                 ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+            )?
+            .execute(params![key, value])?;
+        Ok(())
+    }
+
+    pub fn set_blob(&self, key: &str, value: &[u8]) -> Result<(), IterateError> {
+        self.conn
+            .prepare_cached(
+                "INSERT INTO metadata (key, value) VALUES (?, ?)
+                 ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+            )?
+            .execute(params![key, value])?;
+        Ok(())
+    }
+
+    pub fn delete(&self, key: &str) -> Result<(), IterateError> {
+        self.conn
+            .prepare_cached("DELETE FROM metadata WHERE key = ?")?
+            .execute(params![key])?;
+        Ok(())
+    }
+}
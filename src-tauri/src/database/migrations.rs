@@ -1,23 +1,519 @@
-use rusqlite::Connection;
+use rusqlite::{Connection, OpenFlags, OptionalExtension, TransactionBehavior, params};
+use serde::Serialize;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::{error, info, warn};
 
-const INIT_SQL: &str = include_str!("001_init.sql");
+use crate::error::IterateError;
 
-pub fn run_migrations(conn: &Connection) -> rusqlite::Result<()> {
-    let version: i32 = conn
+/// One schema upgrade. The SQL must not manage transactions itself:
+/// every step runs in its own transaction, which also records `version`.
+struct Migration {
+    version: u32,
+    sql: &'static str,
+}
+
+/// Ordered by version, without gaps.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        sql: include_str!("001_init.sql"),
+    },
+    Migration {
+        version: 2,
+        sql: include_str!("002_key_slots.sql"),
+    },
+    Migration {
+        version: 3,
+        sql: include_str!("003_record_data_keys.sql"),
+    },
+    Migration {
+        version: 4,
+        sql: include_str!("004_key_epochs.sql"),
+    },
+    Migration {
+        version: 5,
+        sql: include_str!("005_record_metadata_mac.sql"),
+    },
+    Migration {
+        version: 6,
+        sql: include_str!("006_record_chain.sql"),
+    },
+    Migration {
+        version: 7,
+        sql: include_str!("007_journal_id.sql"),
+    },
+    Migration {
+        version: 8,
+        sql: include_str!("008_encrypted_topics.sql"),
+    },
+    Migration {
+        version: 9,
+        sql: include_str!("009_record_sealed_metadata.sql"),
+    },
+    Migration {
+        version: 10,
+        sql: include_str!("010_record_keys.sql"),
+    },
+    Migration {
+        version: 11,
+        sql: include_str!("011_repair_key_store.sql"),
+    },
+];
+
+/// The schema version this build writes.
+pub const SCHEMA_VERSION: u32 = MIGRATIONS[MIGRATIONS.len() - 1].version;
+
+/// Oldest schema version whose code can still read what this build writes.
+/// Older readers look for the data keys in the record rows, which 010 moved out.
+pub const MIN_READER_VERSION: u32 = 10;
+
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+const MIN_READER_VERSION_KEY: &str = "min_reader_version";
+
+/// How the journal may be used once `run_migrations` is done.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchemaAccess {
+    ReadWrite,
+    /// Written by a newer version that declared this one as a capable reader.
+    ReadOnly,
+}
+
+/// An upgrade `run_migrations` is about to apply to an existing journal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct SchemaUpgrade {
+    pub from_version: u32,
+    pub to_version: u32,
+}
+
+/// Tells whether opening the journal at `path` will upgrade its schema, without
+/// changing the file. New and current journals need no upgrade.
+/// Opened without `SQLITE_OPEN_CREATE`, so a missing file is an error rather than a new journal.
+pub fn pending_upgrade(path: &Path) -> Result<Option<SchemaUpgrade>, IterateError> {
+    let conn = Connection::open_with_flags(
+        path,
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )?;
+    let version = metadata_version(&conn, SCHEMA_VERSION_KEY)?.unwrap_or(0);
+
+    if version == 0 || version >= SCHEMA_VERSION {
+        return Ok(None);
+    }
+    Ok(Some(SchemaUpgrade {
+        from_version: version,
+        to_version: SCHEMA_VERSION,
+    }))
+}
+
+/// Brings the journal at `path` up to `SCHEMA_VERSION`.
+/// An existing journal is copied aside first. If a step fails, the copy is put back,
+/// so the journal is either fully upgraded or left as it was.
+/// A journal from a newer version is left untouched and opened read-only if its
+/// minimum reader version allows it.
+pub fn run_migrations(path: &Path) -> Result<SchemaAccess, IterateError> {
+    let mut conn = Connection::open(path)?;
+    let version = metadata_version(&conn, SCHEMA_VERSION_KEY)?.unwrap_or(0);
+
+    if version > SCHEMA_VERSION {
+        // Journals that do not declare a minimum reader only trust readers of their own version.
+        let min_reader = metadata_version(&conn, MIN_READER_VERSION_KEY)?.unwrap_or(version);
+        if min_reader <= SCHEMA_VERSION {
+            warn!(
+                "Journal schema {} is newer than {}, opening read-only",
+                version, SCHEMA_VERSION
+            );
+            return Ok(SchemaAccess::ReadOnly);
+        }
+        return Err(IterateError::UnsupportedSchemaVersion {
+            found: version,
+            supported: SCHEMA_VERSION,
+        });
+    }
+    if version == SCHEMA_VERSION {
+        return Ok(SchemaAccess::ReadWrite);
+    }
+    // A new file has nothing to lose.
+    if version == 0 {
+        apply_migrations(&mut conn, version)?;
+        return Ok(SchemaAccess::ReadWrite);
+    }
+
+    let backup = backup_path(path, version);
+    create_backup(&conn, &backup)?;
+    info!(
+        "Upgrading journal schema from {} to {}",
+        version, SCHEMA_VERSION
+    );
+
+    match apply_migrations(&mut conn, version) {
+        Ok(()) => {
+            drop(conn);
+            if let Err(e) = fs::remove_file(&backup) {
+                warn!("Removing the migration backup failed: {}", e);
+            }
+            Ok(SchemaAccess::ReadWrite)
+        }
+        Err(e) => {
+            error!("Migration failed, restoring the backup: {}", e);
+            drop(conn);
+            restore_backup(path, &backup)?;
+            Err(IterateError::MigrationFailed(e.to_string()))
+        }
+    }
+}
+
+/// Runs with foreign keys off, which can only be switched outside a transaction, so
+/// that rebuilding a table does not cascade into the tables referencing it. Each step
+/// has to leave every reference intact instead. Dropped plaintext is overwritten.
+fn apply_migrations(conn: &mut Connection, version: u32) -> Result<(), IterateError> {
+    conn.execute_batch("PRAGMA foreign_keys = OFF; PRAGMA secure_delete = ON;")?;
+
+    for migration in MIGRATIONS.iter().filter(|m| m.version > version) {
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        tx.execute_batch(migration.sql)?;
+        let violations: i64 =
+            tx.query_row("SELECT COUNT(*) FROM pragma_foreign_key_check", [], |row| {
+                row.get(0)
+            })?;
+        if violations > 0 {
+            error!(
+                "Migration {} left {} broken foreign keys",
+                migration.version, violations
+            );
+            return Err(IterateError::DatabaseIntegrity);
+        }
+        set_metadata_version(&tx, SCHEMA_VERSION_KEY, migration.version)?;
+        if migration.version == SCHEMA_VERSION {
+            set_metadata_version(&tx, MIN_READER_VERSION_KEY, MIN_READER_VERSION)?;
+        }
+        tx.commit()?;
+    }
+
+    Ok(())
+}
+
+/// `None` for a file without a schema or without the key.
+fn metadata_version(conn: &Connection, key: &str) -> Result<Option<u32>, IterateError> {
+    let has_metadata: Option<String> = conn
         .query_row(
-            "SELECT value FROM metadata WHERE key = 'schema_version'",
+            "SELECT name FROM sqlite_master WHERE type = 'table' AND name = 'metadata'",
             [],
-            |row| row.get::<_, String>(0).map(|v| v.parse().unwrap_or(0)),
+            |row| row.get(0),
         )
-        .unwrap_or(0);
+        .optional()?;
+    if has_metadata.is_none() {
+        return Ok(None);
+    }
 
-    match version {
-        0 => {
-            conn.execute_batch(INIT_SQL)?;
-        }
-        1 => {}
-        _ => panic!("Unsupported schema version"),
+    let version: Option<String> = conn
+        .query_row(
+            "SELECT value FROM metadata WHERE key = ?",
+            params![key],
+            |row| row.get(0),
+        )
+        .optional()?;
+    version
+        .map(|v| {
+            v.parse()
+                .map_err(|_| IterateError::Internal(format!("invalid {} '{}'", key, v)))
+        })
+        .transpose()
+}
+
+fn set_metadata_version(conn: &Connection, key: &str, version: u32) -> Result<(), IterateError> {
+    conn.execute(
+        "INSERT INTO metadata (key, value) VALUES (?, ?)
+         ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+        params![key, version.to_string()],
+    )?;
+    Ok(())
+}
+
+fn backup_path(path: &Path, version: u32) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(format!(".v{}.bak", version));
+    PathBuf::from(name)
+}
+
+/// `VACUUM INTO` writes a consistent copy, including changes still in the WAL.
+fn create_backup(conn: &Connection, backup: &Path) -> Result<(), IterateError> {
+    if backup.exists() {
+        fs::remove_file(backup)?;
     }
+    conn.execute(
+        "VACUUM INTO ?",
+        params![backup.to_string_lossy().into_owned()],
+    )?;
+    Ok(())
+}
 
+/// Puts the backup in place of the journal. The WAL and shared-memory files belong
+/// to the failed attempt and are removed with it.
+fn restore_backup(path: &Path, backup: &Path) -> Result<(), IterateError> {
+    for suffix in ["-wal", "-shm"] {
+        let mut name = OsString::from(path.as_os_str());
+        name.push(suffix);
+        let sidecar = PathBuf::from(name);
+        if sidecar.exists() {
+            fs::remove_file(sidecar)?;
+        }
+    }
+    fs::rename(backup, path)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::testing::{TempJournal, service_keys};
+    use crate::database::{TopicRepository, open_database};
+    use crate::services::gatekeeper::{add_recovery_key, initialize_key_store, verify_password};
+    use rand::rngs::OsRng;
+    use uuid::Uuid;
+
+    /// A journal as the release with schema `version` left it.
+    fn journal_at(journal: &TempJournal, version: u32) -> Connection {
+        let conn = Connection::open(&journal.0).unwrap();
+        for migration in MIGRATIONS.iter().filter(|m| m.version <= version) {
+            conn.execute_batch(migration.sql).unwrap();
+        }
+        set_metadata_version(&conn, SCHEMA_VERSION_KEY, version).unwrap();
+        conn
+    }
+
+    #[test]
+    fn keeps_record_topics_through_the_topic_rebuild() {
+        let journal = TempJournal::in_temp_dir();
+        let conn = journal_at(&journal, 7);
+        let (topic_id, record_id) = (Uuid::now_v7(), Uuid::now_v7());
+        conn.execute(
+            "INSERT INTO topic (topic_id, name, created_at_utc) VALUES (?, 'Work', 1)",
+            params![topic_id.as_bytes()],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO record (record_id, encrypted_content, is_summarized, is_summary_record,
+                is_archived, is_deleted, created_at_utc, last_modified_at_utc)
+             VALUES (?, x'00', 0, 0, 0, 0, 1, 1)",
+            params![record_id.as_bytes()],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO record_topic (record_id, topic_id) VALUES (?, ?)",
+            params![record_id.as_bytes(), topic_id.as_bytes()],
+        )
+        .unwrap();
+        drop(conn);
+
+        let conn = open_database(journal.0.clone()).expect("the journal is upgraded");
+        let keys = service_keys();
+        let topics = TopicRepository::new(&conn, &keys);
+        assert_eq!(topics.encrypt_legacy_rows(&mut OsRng).unwrap(), 1);
+
+        let tagged: Vec<u8> = conn
+            .query_row(
+                "SELECT topic_id FROM record_topic WHERE record_id = ?",
+                params![record_id.as_bytes()],
+                |row| row.get(0),
+            )
+            .expect("the tag survived");
+        assert_eq!(tagged, topic_id.as_bytes());
+        assert_eq!(topics.list_topics().unwrap()[0].name, "Work");
+
+        let legacy_tables: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE name LIKE 'legacy_%'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(legacy_tables, 0);
+    }
+
+    #[test]
+    fn moves_the_data_keys_out_of_the_records() {
+        let journal = TempJournal::in_temp_dir();
+        let conn = journal_at(&journal, 9);
+        let (keyed, legacy) = (Uuid::now_v7(), Uuid::now_v7());
+        for (id, wrapped_data_key) in [(keyed, Some(b"wrapped key".to_vec())), (legacy, None)] {
+            conn.execute(
+                "INSERT INTO record (record_id, encrypted_content, wrapped_data_key,
+                    is_summarized, is_summary_record, is_archived, is_deleted,
+                    created_at_utc, last_modified_at_utc)
+                 VALUES (?, x'00', ?, 0, 0, 0, 0, 1, 1)",
+                params![id.as_bytes(), wrapped_data_key],
+            )
+            .unwrap();
+        }
+        drop(conn);
+
+        run_migrations(&journal.0).unwrap();
+
+        let conn = Connection::open(&journal.0).unwrap();
+        let moved: Vec<(Vec<u8>, Vec<u8>)> = conn
+            .prepare("SELECT record_id, wrapped_data_key FROM record_key")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            moved,
+            vec![(keyed.as_bytes().to_vec(), b"wrapped key".to_vec())]
+        );
+        assert!(conn.prepare("SELECT wrapped_data_key FROM record").is_err());
+    }
+
+    #[test]
+    fn repairs_a_key_store_left_by_the_original_init_schema() {
+        let journal = TempJournal::in_temp_dir();
+        let password = b"correct horse battery staple";
+        let mut conn = open_database(journal.0.clone()).unwrap();
+        initialize_key_store(&mut OsRng, &mut conn, password, None).unwrap();
+
+        // The slots as a hand-repaired copy of the original 001_init.sql left them.
+        conn.execute_batch(
+            "ALTER TABLE key_store RENAME TO key_store_slots;
+             CREATE TABLE key_store (
+                 slot_id INTEGER PRIMARY KEY AUTOINCREMENT,
+                 label TEXT NOT NULL,
+                 kdf_strategy INTEGER NOT NULL,
+                 kdf_salt BLOB NOT NULL,
+                 kdf_params TEXT NOT NULL,
+                 wrapped_key BLOB NOT NULL,
+                 created_at_utc INTEGER NOT NULL
+             );
+             INSERT INTO key_store
+                 SELECT slot_id, label, 0, x'00', CAST(kdf_params AS TEXT), wrapped_key, created_at_utc
+                 FROM key_store_slots;
+             DROP TABLE key_store_slots;
+             CREATE TABLE integrity_check (id INTEGER PRIMARY KEY);
+             CREATE UNIQUE INDEX idx_integrity_singleton ON integrity_check(id);",
+        )
+        .unwrap();
+        set_metadata_version(&conn, SCHEMA_VERSION_KEY, 10).unwrap();
+        assert!(add_recovery_key(&mut OsRng, &conn, password, None).is_err());
+        drop(conn);
+
+        let mut conn = open_database(journal.0.clone()).expect("the journal is upgraded");
+        let columns: Vec<String> = conn
+            .prepare("SELECT name FROM pragma_table_info('key_store')")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            columns,
+            [
+                "slot_id",
+                "label",
+                "kdf_params",
+                "wrapped_key",
+                "created_at_utc"
+            ]
+        );
+        let text_params: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM key_store WHERE typeof(kdf_params) != 'blob'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(text_params, 0);
+        let stray_index: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE name = 'idx_integrity_singleton'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(stray_index, 0);
+
+        verify_password(&mut OsRng, &mut conn, password, None).expect("the password unlocks");
+        add_recovery_key(&mut OsRng, &conn, password, None).expect("slots can be added again");
+    }
+
+    #[test]
+    fn upgrades_without_leaving_a_backup_behind() {
+        let journal = TempJournal::in_temp_dir();
+        drop(journal_at(&journal, 6));
+
+        assert_eq!(
+            pending_upgrade(&journal.0).unwrap(),
+            Some(SchemaUpgrade {
+                from_version: 6,
+                to_version: SCHEMA_VERSION
+            })
+        );
+        assert_eq!(run_migrations(&journal.0).unwrap(), SchemaAccess::ReadWrite);
+
+        let conn = Connection::open(&journal.0).unwrap();
+        assert_eq!(
+            metadata_version(&conn, SCHEMA_VERSION_KEY).unwrap(),
+            Some(SCHEMA_VERSION)
+        );
+        assert_eq!(pending_upgrade(&journal.0).unwrap(), None);
+        assert!(!backup_path(&journal.0, 6).exists());
+    }
+
+    #[test]
+    fn restores_the_backup_when_a_step_fails() {
+        let journal = TempJournal::in_temp_dir();
+        let conn = journal_at(&journal, 6);
+        conn.execute(
+            "INSERT INTO topic (topic_id, name, created_at_utc) VALUES (?, 'Work', 1)",
+            params![Uuid::now_v7().as_bytes()],
+        )
+        .unwrap();
+        // 007 applies, then 008 fails to create its table aside.
+        conn.execute_batch("CREATE TABLE legacy_topic (topic_id BLOB)")
+            .unwrap();
+        drop(conn);
+
+        assert!(matches!(
+            run_migrations(&journal.0),
+            Err(IterateError::MigrationFailed(_))
+        ));
+
+        let conn = Connection::open(&journal.0).unwrap();
+        assert_eq!(
+            metadata_version(&conn, SCHEMA_VERSION_KEY).unwrap(),
+            Some(6)
+        );
+        let name: String = conn
+            .query_row("SELECT name FROM topic", [], |row| row.get(0))
+            .expect("the topic is back");
+        assert_eq!(name, "Work");
+        assert!(!backup_path(&journal.0, 6).exists());
+    }
+
+    #[test]
+    fn opens_a_newer_journal_only_if_it_allows_this_reader() {
+        let journal = TempJournal::in_temp_dir();
+        let conn = journal_at(&journal, SCHEMA_VERSION);
+        set_metadata_version(&conn, SCHEMA_VERSION_KEY, SCHEMA_VERSION + 1).unwrap();
+
+        // Without a minimum reader, only its own version may open it.
+        assert!(matches!(
+            run_migrations(&journal.0),
+            Err(IterateError::UnsupportedSchemaVersion { found, supported })
+                if found == SCHEMA_VERSION + 1 && supported == SCHEMA_VERSION
+        ));
+
+        set_metadata_version(&conn, MIN_READER_VERSION_KEY, SCHEMA_VERSION).unwrap();
+        assert_eq!(run_migrations(&journal.0).unwrap(), SchemaAccess::ReadOnly);
+        assert_eq!(pending_upgrade(&journal.0).unwrap(), None);
+
+        set_metadata_version(&conn, MIN_READER_VERSION_KEY, SCHEMA_VERSION + 1).unwrap();
+        assert!(matches!(
+            run_migrations(&journal.0),
+            Err(IterateError::UnsupportedSchemaVersion { .. })
+        ));
+        assert_eq!(
+            metadata_version(&conn, SCHEMA_VERSION_KEY).unwrap(),
+            Some(SCHEMA_VERSION + 1)
+        );
+    }
+}
@@ -1,6 +1,15 @@
 pub mod migrations;
+pub mod chain;
 pub mod connection;
+pub mod metadata;
 pub mod records;
+pub mod topics;
 
-pub use connection::open_database;
+#[cfg(test)]
+pub(crate) mod testing;
+
+pub use chain::RecordChain;
+pub use connection::{check_integrity, ensure_writable, is_read_only, open_database};
+pub use metadata::MetadataRepository;
 pub use records::RecordRepository;
+pub use topics::TopicRepository;
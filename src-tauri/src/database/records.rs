@@ -1,12 +1,34 @@
+use crate::crypto::aead::{decrypt, encrypt};
+use crate::crypto::cryptoenvelope::CryptoEnvelope;
+use crate::crypto::mac::{compute_mac, verify_mac};
+use crate::crypto::servicekeys::ServiceKeys;
+use crate::database::MetadataRepository;
+use crate::database::chain::{ChainOperation, ChainState, RecordChain};
 use crate::error::IterateError;
-use chrono::Utc;
+use chrono::{DateTime, Datelike, Utc};
+use rand::TryRngCore;
 use rusqlite::{Connection, Row, params};
-use tracing::error;
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
 use uuid::Uuid;
+use zeroize::Zeroizing;
+
+const METADATA_MAC_DOMAIN: &[u8] = b"record-metadata-v1";
+const SEALED_METADATA_AAD: &[u8] = b"record.metadata";
+/// Set by migration 005 for the rows that predate metadata MACs, and cleared once
+/// they have one. Without it, or once the chain has started, a missing MAC is tampering.
+const METADATA_MACS_PENDING: &str = "record_macs_pending";
+/// Set while privacy mode is on.
+const PRIVACY_MODE: &str = "privacy_mode";
+/// Records with their wrapped data keys, which live in a table of their own.
+const SELECT_RECORDS: &str = "SELECT record.*, record_key.wrapped_data_key
+    FROM record LEFT JOIN record_key USING (record_id)";
 
 pub struct RecordRow {
     pub id: Uuid,
     pub encrypted_content: Vec<u8>,
+    pub wrapped_data_key: Option<Vec<u8>>,
+    pub key_epoch: i64,
     pub sentiment_score: Option<f32>,
     pub is_summarized: bool,
     pub is_summary_record: bool,
@@ -15,104 +37,881 @@ pub struct RecordRow {
     pub created_at_utc: i64,
     pub last_modified_at_utc: i64,
     pub deleted_at_utc: Option<i64>,
+    pub metadata_mac: Option<Vec<u8>>,
+    /// Set if the row was written in privacy mode. The fields above always hold
+    /// the exact values, decrypted from here where necessary.
+    pub sealed_metadata: Option<Vec<u8>>,
+}
+
+impl RecordRow {
+    /// Canonical encoding of the metadata columns covered by `metadata_mac`.
+    /// Rows in privacy mode are authenticated with their exact values.
+    fn metadata_bytes(&self) -> Result<Vec<u8>, IterateError> {
+        postcard::to_stdvec(&(
+            self.id.as_bytes(),
+            self.key_epoch,
+            self.sentiment_score,
+            self.is_summarized,
+            self.is_summary_record,
+            self.is_archived,
+            self.is_deleted,
+            self.created_at_utc,
+            self.last_modified_at_utc,
+            self.deleted_at_utc,
+        ))
+        .map_err(|e| IterateError::PostCardSerializationFailed(e.to_string()))
+    }
+}
+
+/// The columns that privacy mode moves into `sealed_metadata`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct RecordMetadata {
+    sentiment_score: Option<f32>,
+    is_summarized: bool,
+    is_summary_record: bool,
+    is_archived: bool,
+    is_deleted: bool,
+    created_at_utc: i64,
+    last_modified_at_utc: i64,
+    deleted_at_utc: Option<i64>,
+}
+
+impl RecordMetadata {
+    fn of(row: &RecordRow) -> Self {
+        Self {
+            sentiment_score: row.sentiment_score,
+            is_summarized: row.is_summarized,
+            is_summary_record: row.is_summary_record,
+            is_archived: row.is_archived,
+            is_deleted: row.is_deleted,
+            created_at_utc: row.created_at_utc,
+            last_modified_at_utc: row.last_modified_at_utc,
+            deleted_at_utc: row.deleted_at_utc,
+        }
+    }
+
+    /// What stays readable in privacy mode: the month the record was created in.
+    fn concealed(&self) -> Self {
+        let bucket = month_bucket(self.created_at_utc);
+        Self {
+            sentiment_score: None,
+            is_summarized: false,
+            is_summary_record: false,
+            is_archived: false,
+            is_deleted: false,
+            created_at_utc: bucket,
+            last_modified_at_utc: bucket,
+            deleted_at_utc: None,
+        }
+    }
+
+    fn apply(self, row: &mut RecordRow) {
+        row.sentiment_score = self.sentiment_score;
+        row.is_summarized = self.is_summarized;
+        row.is_summary_record = self.is_summary_record;
+        row.is_archived = self.is_archived;
+        row.is_deleted = self.is_deleted;
+        row.created_at_utc = self.created_at_utc;
+        row.last_modified_at_utc = self.last_modified_at_utc;
+        row.deleted_at_utc = self.deleted_at_utc;
+    }
+}
+
+/// Returns true if newly written records keep their metadata encrypted.
+pub fn privacy_mode(conn: &Connection) -> Result<bool, IterateError> {
+    Ok(MetadataRepository::new(conn).get(PRIVACY_MODE)?.is_some())
+}
+
+/// Returns the id for a new row. UUIDv7 ids embed their creation time, which
+/// privacy mode conceals, so random ones are used while it is on.
+pub fn new_row_id(conn: &Connection) -> Result<Uuid, IterateError> {
+    Ok(if privacy_mode(conn)? {
+        Uuid::new_v4()
+    } else {
+        Uuid::now_v7()
+    })
+}
+
+/// The most rows `fetch_latest` returns. Each of them is decrypted, so a larger
+/// request is capped rather than reading the whole journal at once.
+pub const MAX_LATEST_RECORDS: usize = 1000;
+
+/// A row that failed to decrypt or authenticate.
+#[derive(Debug)]
+pub struct UnreadableRecord {
+    pub id: Uuid,
+    pub error: IterateError,
 }
 
+/// Reads and writes records. Every row read is checked against its metadata MAC,
+/// keyed with `ServiceKeys::meta`, and every row written gets a fresh one.
+/// Writes and deletes also extend the `RecordChain`.
+///
+/// In privacy mode the exact timestamps, sentiment and flags are encrypted with
+/// `ServiceKeys::record_metadata`, and queries on them filter after decryption.
 pub struct RecordRepository<'a> {
     conn: &'a Connection,
+    meta_key: &'a Zeroizing<[u8; 32]>,
+    record_metadata_key: &'a Zeroizing<[u8; 32]>,
+    chain: RecordChain<'a>,
 }
 
 impl<'a> RecordRepository<'a> {
-    pub fn new(conn: &'a Connection) -> Self {
-        Self { conn }
+    pub fn new(conn: &'a Connection, keys: &'a ServiceKeys) -> Self {
+        Self {
+            conn,
+            meta_key: &keys.meta,
+            record_metadata_key: &keys.record_metadata,
+            chain: RecordChain::new(conn, &keys.chain),
+        }
     }
 
-    pub fn insert(
+    pub fn insert<R: TryRngCore>(
         &self,
+        rng: &mut R,
         id: &Uuid,
         content: &[u8],
+        wrapped_data_key: &[u8],
         sentiment: Option<f32>,
     ) -> Result<(), IterateError> {
         let now = Utc::now().timestamp();
+        let row = RecordRow {
+            id: *id,
+            encrypted_content: content.to_vec(),
+            wrapped_data_key: Some(wrapped_data_key.to_vec()),
+            key_epoch: self.current_key_epoch()?,
+            sentiment_score: sentiment,
+            is_summarized: false,
+            is_summary_record: false,
+            is_archived: false,
+            is_deleted: false,
+            created_at_utc: now,
+            last_modified_at_utc: now,
+            deleted_at_utc: None,
+            metadata_mac: None,
+            sealed_metadata: None,
+        };
+        let mac = self.mac(&row)?;
+        let (stored, sealed_metadata) = self.stored_metadata(rng, &row)?;
 
-        self.conn.execute(
-            "INSERT INTO record (
-                record_id, encrypted_content, sentiment_score, 
+        self.atomically(|| {
+            self.conn
+                .prepare_cached(
+                    "INSERT INTO record (
+                record_id, encrypted_content, key_epoch, sentiment_score,
                 is_summarized, is_summary_record, is_archived, is_deleted,
-                created_at_utc, last_modified_at_utc
-            ) VALUES (?, ?, ?, 0, 0, 0, 0, ?, ?)",
-            params![id.as_bytes(), content, sentiment, now, now],
-        )?;
+                created_at_utc, last_modified_at_utc, deleted_at_utc, metadata_mac, sealed_metadata
+            ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                )?
+                .execute(params![
+                    row.id.as_bytes(),
+                    row.encrypted_content,
+                    row.key_epoch,
+                    stored.sentiment_score,
+                    stored.is_summarized as i32,
+                    stored.is_summary_record as i32,
+                    stored.is_archived as i32,
+                    stored.is_deleted as i32,
+                    stored.created_at_utc,
+                    stored.last_modified_at_utc,
+                    stored.deleted_at_utc,
+                    mac,
+                    sealed_metadata
+                ])?;
+            self.store_data_key(&row)?;
+            self.chain
+                .append(&row.id, ChainOperation::Write, Some(mac.as_slice()))
+        })
+    }
 
-        Ok(())
+    pub fn update<R: TryRngCore>(
+        &self,
+        rng: &mut R,
+        id: &Uuid,
+        content: &[u8],
+        wrapped_data_key: &[u8],
+    ) -> Result<(), IterateError> {
+        let mut row = self.get_record(*id)?;
+        row.encrypted_content = content.to_vec();
+        row.wrapped_data_key = Some(wrapped_data_key.to_vec());
+        row.key_epoch = self.current_key_epoch()?;
+        row.last_modified_at_utc = Utc::now().timestamp();
+
+        self.atomically(|| {
+            let mac = self.write_existing(rng, &row)?;
+            self.chain
+                .append(&row.id, ChainOperation::Write, Some(mac.as_slice()))
+        })
     }
 
-    pub fn update(&self, id: &Uuid, content: &[u8]) -> Result<(), IterateError> {
-        let now = Utc::now().timestamp();
+    /// Replaces the ciphertext after a key rotation without touching the modification time.
+    /// The MAC is recomputed with this repository's meta key. The chain is not extended,
+    /// since the rotation restarts it under the new chain key once all records are done.
+    pub fn reencrypt<R: TryRngCore>(
+        &self,
+        rng: &mut R,
+        row: &RecordRow,
+        content: &[u8],
+        wrapped_data_key: &[u8],
+        key_epoch: i64,
+    ) -> Result<(), IterateError> {
+        let row = RecordRow {
+            id: row.id,
+            encrypted_content: content.to_vec(),
+            wrapped_data_key: Some(wrapped_data_key.to_vec()),
+            key_epoch,
+            sentiment_score: row.sentiment_score,
+            is_summarized: row.is_summarized,
+            is_summary_record: row.is_summary_record,
+            is_archived: row.is_archived,
+            is_deleted: row.is_deleted,
+            created_at_utc: row.created_at_utc,
+            last_modified_at_utc: row.last_modified_at_utc,
+            deleted_at_utc: row.deleted_at_utc,
+            metadata_mac: None,
+            sealed_metadata: None,
+        };
 
-        self.conn.execute(
-            "UPDATE record SET 
-                encrypted_content = ?, 
-                last_modified_at_utc = ? 
-            WHERE record_id = ?",
-            params![content, now, id.as_bytes()],
-        )?;
+        self.write_existing(rng, &row).map(|_| ())
+    }
 
-        Ok(())
+    /// Destroys the record's data key and removes the record.
+    /// `secure_delete` overwrites the freed pages and the checkpoint flushes
+    /// older copies out of the WAL. Any copy of the ciphertext this file still holds,
+    /// e.g. in pages the filesystem moved, cannot be opened once the key is gone.
+    /// Copies of the journal taken before the deletion hold the key as well.
+    pub fn delete_permanently(&self, id: &Uuid) -> Result<(), IterateError> {
+        self.delete(id)?;
+        checkpoint_wal(self.conn)
+    }
+
+    /// Removes the record without checkpointing, for callers that delete in bulk.
+    pub fn delete(&self, id: &Uuid) -> Result<(), IterateError> {
+        self.atomically(|| {
+            self.conn
+                .prepare_cached("DELETE FROM record_key WHERE record_id = ?")?
+                .execute(params![id.as_bytes()])?;
+            let deleted = self
+                .conn
+                .prepare_cached("DELETE FROM record WHERE record_id = ?")?
+                .execute(params![id.as_bytes()])?;
+            if deleted == 0 {
+                return Err(IterateError::RecordNotFound);
+            }
+
+            self.chain.append(id, ChainOperation::Delete, None)
+        })
     }
 
     pub fn get_record(&self, id: Uuid) -> Result<RecordRow, IterateError> {
-        self.conn
-            .query_row(
-                "SELECT * FROM record WHERE record_id = ?",
-                params![id.as_bytes()],
-                |row| {
-                    Ok(RecordRow {
-                        id,
-                        encrypted_content: row.get("encrypted_content")?,
-                        sentiment_score: row.get("sentiment_score")?,
-                        is_summarized: row.get::<_, i32>("is_summarized")? != 0,
-                        is_summary_record: row.get::<_, i32>("is_summary_record")? != 0,
-                        is_archived: row.get::<_, i32>("is_archived")? != 0,
-                        is_deleted: row.get::<_, i32>("is_deleted")? != 0,
-                        created_at_utc: row.get("created_at_utc")?,
-                        last_modified_at_utc: row.get("last_modified_at_utc")?,
-                        deleted_at_utc: row.get("deleted_at_utc")?,
-                    })
-                },
-            )
+        let row = self
+            .conn
+            .prepare_cached(&format!("{SELECT_RECORDS} WHERE record_id = ?"))?
+            .query_row(params![id.as_bytes()], map_row)
             .map_err(|e| {
                 error!("DB entry missing: {}", e);
-                return IterateError::RecordNotFound;
-            })
+                IterateError::RecordNotFound
+            })?;
+
+        self.open(row)
     }
 
-    pub fn fetch_latest(&self, limit: usize) -> Result<Vec<RecordRow>, IterateError> {
-        let mut stmt = self.conn.prepare(
-            "SELECT * FROM record 
-             WHERE is_deleted = 0 
-             ORDER BY created_at_utc DESC 
-             LIMIT ?",
-        )?;
-        let safe_limit = std::cmp::min(limit, 1000) as i64;
-        let rows = stmt.query_map([safe_limit], |row| {
-            Ok(RecordRow {
-                id: Uuid::from_slice(&row.get::<_, Vec<u8>>("record_id")?).unwrap_or_default(),
-                encrypted_content: row.get("encrypted_content")?,
-                sentiment_score: row.get("sentiment_score")?,
-                is_summarized: row.get::<_, i32>("is_summarized")? != 0,
-                is_summary_record: row.get::<_, i32>("is_summary_record")? != 0,
-                is_archived: row.get::<_, i32>("is_archived")? != 0,
-                is_deleted: row.get::<_, i32>("is_deleted")? != 0,
-                created_at_utc: row.get("created_at_utc")?,
-                last_modified_at_utc: row.get("last_modified_at_utc")?,
-                deleted_at_utc: row.get("deleted_at_utc")?,
-            })
-        })?;
+    /// Returns the newest entries, neither deleted nor summaries, at most `limit` of them
+    /// and never more than `MAX_LATEST_RECORDS`. A row that cannot be opened is returned
+    /// as an `UnreadableRecord` in its place, so the rows around it still load.
+    /// Rows in privacy mode store neither the flags nor the exact creation time, so while
+    /// it is on all of them are read, filtered and sorted after decryption. An unreadable
+    /// one is sorted by the month it was created in.
+    pub fn fetch_latest(
+        &self,
+        limit: usize,
+    ) -> Result<Vec<Result<RecordRow, UnreadableRecord>>, IterateError> {
+        let limit = limit.min(MAX_LATEST_RECORDS);
+        if !privacy_mode(self.conn)? {
+            let mut stmt = self.conn.prepare_cached(&format!(
+                "{SELECT_RECORDS}
+                 WHERE is_deleted = 0 AND is_summary_record = 0
+                 ORDER BY created_at_utc DESC
+                 LIMIT ?"
+            ))?;
+            let rows = stmt
+                .query_map(params![limit as i64], map_row)?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            return Ok(rows.into_iter().map(|row| self.try_open(row)).collect());
+        }
+
+        let mut stmt = self
+            .conn
+            .prepare_cached(&format!("{SELECT_RECORDS} WHERE is_deleted = 0"))?;
+        let rows = stmt
+            .query_map([], map_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let mut results = Vec::new();
+        for row in rows {
+            let month = row.created_at_utc;
+            match self.try_open(row) {
+                Ok(row) if row.is_deleted || row.is_summary_record => {}
+                Ok(row) => results.push((row.created_at_utc, Ok(row))),
+                Err(unreadable) => results.push((month, Err(unreadable))),
+            }
+        }
+        results.sort_by_key(|(created_at_utc, _)| std::cmp::Reverse(*created_at_utc));
+        results.truncate(limit);
+        Ok(results.into_iter().map(|(_, row)| row).collect())
+    }
+
+    /// Records still encrypted under a master key older than `key_epoch`, deleted ones included.
+    pub fn fetch_before_epoch(
+        &self,
+        key_epoch: i64,
+        limit: usize,
+    ) -> Result<Vec<RecordRow>, IterateError> {
+        let mut stmt = self.conn.prepare(&format!(
+            "{SELECT_RECORDS}
+             WHERE key_epoch < ?
+             LIMIT ?"
+        ))?;
+        let rows = stmt.query_map(params![key_epoch, limit as i64], map_row)?;
 
         let mut results = Vec::with_capacity(limit);
         for row in rows {
-            results.push(row?);
+            results.push(self.open(row?)?);
         }
         Ok(results)
     }
+
+    pub fn count_before_epoch(&self, key_epoch: i64) -> Result<usize, IterateError> {
+        let count: i64 = self.conn.query_row(
+            "SELECT COUNT(*) FROM record WHERE key_epoch < ?",
+            params![key_epoch],
+            |row| row.get(0),
+        )?;
+        Ok(count as usize)
+    }
+
+    /// Soft-deleted records whose deletion is older than `cutoff`.
+    /// Rows that fail authentication are left out, so tampered flags cannot trigger a purge.
+    pub fn fetch_deleted_before(&self, cutoff: i64) -> Result<Vec<RecordRow>, IterateError> {
+        let mut stmt = self.conn.prepare(&format!(
+            "{SELECT_RECORDS}
+             WHERE (is_deleted = 1 AND deleted_at_utc < ?)
+             OR sealed_metadata IS NOT NULL"
+        ))?;
+        let rows = stmt.query_map([cutoff], map_row)?;
+
+        let mut results = Vec::new();
+        for row in rows {
+            let row = row?;
+            let id = row.id;
+            match self.open(row) {
+                Ok(row) => {
+                    if row.is_deleted && row.deleted_at_utc.is_some_and(|at| at < cutoff) {
+                        results.push(row);
+                    }
+                }
+                Err(e) => warn!("Skipping record {}: {}", id, e),
+            }
+        }
+        Ok(results)
+    }
+
+    /// Turns privacy mode on or off and rewrites every record in the new form.
+    /// The metadata MACs cover the exact values, so they and the chain stay as they are.
+    /// The caller checkpoints the WAL, so the previous form of the rows does not linger there.
+    pub fn set_privacy_mode<R: TryRngCore>(
+        &self,
+        rng: &mut R,
+        enabled: bool,
+    ) -> Result<usize, IterateError> {
+        let mut stmt = self.conn.prepare(SELECT_RECORDS)?;
+        let rows = stmt
+            .query_map([], map_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let count = rows.len();
+
+        self.atomically(|| {
+            let metadata = MetadataRepository::new(self.conn);
+            if enabled {
+                metadata.set(PRIVACY_MODE, "1")?;
+            } else {
+                metadata.delete(PRIVACY_MODE)?;
+            }
+
+            for row in rows {
+                let row = self.open(row)?;
+                self.write_existing(rng, &row)?;
+            }
+            Ok(())
+        })?;
+
+        Ok(count)
+    }
+
+    /// Records whose id is a UUIDv7, which embeds their creation time, deleted ones included.
+    pub fn fetch_time_ordered(&self) -> Result<Vec<RecordRow>, IterateError> {
+        let mut stmt = self.conn.prepare(SELECT_RECORDS)?;
+        let rows = stmt
+            .query_map([], map_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        rows.into_iter()
+            .filter(|row| row.id.get_version_num() == 7)
+            .map(|row| self.open(row))
+            .collect()
+    }
+
+    /// Moves the record to `new_id`, with its content re-encrypted for that id.
+    /// Its topics and data key move along. The chain is not extended, since it still
+    /// names the old id; the caller restarts it once every record has moved.
+    pub fn rekey<R: TryRngCore>(
+        &self,
+        rng: &mut R,
+        row: &RecordRow,
+        new_id: &Uuid,
+        content: &[u8],
+        wrapped_data_key: &[u8],
+    ) -> Result<(), IterateError> {
+        let moved = RecordRow {
+            id: *new_id,
+            encrypted_content: content.to_vec(),
+            wrapped_data_key: Some(wrapped_data_key.to_vec()),
+            key_epoch: row.key_epoch,
+            sentiment_score: row.sentiment_score,
+            is_summarized: row.is_summarized,
+            is_summary_record: row.is_summary_record,
+            is_archived: row.is_archived,
+            is_deleted: row.is_deleted,
+            created_at_utc: row.created_at_utc,
+            last_modified_at_utc: row.last_modified_at_utc,
+            deleted_at_utc: row.deleted_at_utc,
+            metadata_mac: None,
+            sealed_metadata: None,
+        };
+
+        self.atomically(|| {
+            // The tags and the key point at the old id until they are moved as well.
+            self.conn.execute_batch("PRAGMA defer_foreign_keys = ON")?;
+            for sql in [
+                "UPDATE record SET record_id = ? WHERE record_id = ?",
+                "UPDATE record_topic SET record_id = ? WHERE record_id = ?",
+            ] {
+                self.conn
+                    .prepare_cached(sql)?
+                    .execute(params![new_id.as_bytes(), row.id.as_bytes()])?;
+            }
+            self.conn
+                .prepare_cached("DELETE FROM record_key WHERE record_id = ?")?
+                .execute(params![row.id.as_bytes()])?;
+
+            self.write_existing(rng, &moved).map(|_| ())
+        })
+    }
+
+    /// Adds MACs to rows written before metadata authentication existed, and starts the
+    /// record chain while migration 006 left it pending.
+    /// Rows are only authenticated while migration 005 left them pending and before
+    /// the chain started, so a row that was present after the upgrade is never re-MACed,
+    /// and a chain that went missing is reported by `ChainState` rather than restarted.
+    pub fn authenticate_legacy_rows(&self) -> Result<usize, IterateError> {
+        let metadata = MetadataRepository::new(self.conn);
+        let chain_pending = self.chain.state()? == ChainState::Pending;
+        if !chain_pending && metadata.get(METADATA_MACS_PENDING)?.is_none() {
+            return Ok(0);
+        }
+
+        let rows = if self.legacy_rows_allowed()? {
+            let mut stmt = self
+                .conn
+                .prepare(&format!("{SELECT_RECORDS} WHERE metadata_mac IS NULL"))?;
+            stmt.query_map([], map_row)?
+                .collect::<rusqlite::Result<Vec<_>>>()?
+        } else {
+            Vec::new()
+        };
+
+        self.atomically(|| {
+            for row in &rows {
+                self.conn
+                    .prepare_cached("UPDATE record SET metadata_mac = ? WHERE record_id = ?")?
+                    .execute(params![self.mac(row)?, row.id.as_bytes()])?;
+            }
+
+            if chain_pending {
+                self.chain.restart()?;
+            }
+            metadata.delete(METADATA_MACS_PENDING)?;
+            Ok(rows.len())
+        })
+    }
+
+    /// Returns true while rows without a MAC are ones the migration left behind.
+    fn legacy_rows_allowed(&self) -> Result<bool, IterateError> {
+        Ok(MetadataRepository::new(self.conn)
+            .get(METADATA_MACS_PENDING)?
+            .is_some()
+            && self.chain.state()? == ChainState::Pending)
+    }
+
+    /// Stores the row and returns its new metadata MAC.
+    fn write_existing<R: TryRngCore>(
+        &self,
+        rng: &mut R,
+        row: &RecordRow,
+    ) -> Result<Vec<u8>, IterateError> {
+        let mac = self.mac(row)?;
+        let (stored, sealed_metadata) = self.stored_metadata(rng, row)?;
+        let updated = self
+            .conn
+            .prepare_cached(
+                "UPDATE record SET
+                encrypted_content = ?,
+                key_epoch = ?,
+                sentiment_score = ?,
+                is_summarized = ?,
+                is_summary_record = ?,
+                is_archived = ?,
+                is_deleted = ?,
+                created_at_utc = ?,
+                last_modified_at_utc = ?,
+                deleted_at_utc = ?,
+                metadata_mac = ?,
+                sealed_metadata = ?
+            WHERE record_id = ?",
+            )?
+            .execute(params![
+                row.encrypted_content,
+                row.key_epoch,
+                stored.sentiment_score,
+                stored.is_summarized as i32,
+                stored.is_summary_record as i32,
+                stored.is_archived as i32,
+                stored.is_deleted as i32,
+                stored.created_at_utc,
+                stored.last_modified_at_utc,
+                stored.deleted_at_utc,
+                mac,
+                sealed_metadata,
+                row.id.as_bytes()
+            ])?;
+        if updated == 0 {
+            return Err(IterateError::RecordNotFound);
+        }
+        self.store_data_key(row)?;
+
+        Ok(mac)
+    }
+
+    /// Stores the row's wrapped data key. Legacy rows without one have nothing to store.
+    fn store_data_key(&self, row: &RecordRow) -> Result<(), IterateError> {
+        if let Some(wrapped_data_key) = &row.wrapped_data_key {
+            self.conn
+                .prepare_cached(
+                    "INSERT OR REPLACE INTO record_key (record_id, wrapped_data_key) VALUES (?, ?)",
+                )?
+                .execute(params![row.id.as_bytes(), wrapped_data_key])?;
+        }
+        Ok(())
+    }
+
+    /// The metadata columns to write for `row`, plus the sealed copy in privacy mode.
+    fn stored_metadata<R: TryRngCore>(
+        &self,
+        rng: &mut R,
+        row: &RecordRow,
+    ) -> Result<(RecordMetadata, Option<Vec<u8>>), IterateError> {
+        let metadata = RecordMetadata::of(row);
+        if !privacy_mode(self.conn)? {
+            return Ok((metadata, None));
+        }
+
+        let plaintext = Zeroizing::new(
+            postcard::to_stdvec(&metadata)
+                .map_err(|e| IterateError::PostCardSerializationFailed(e.to_string()))?,
+        );
+        let sealed = encrypt(
+            rng,
+            self.record_metadata_key,
+            &plaintext,
+            &sealed_metadata_aad(&row.id),
+        )?
+        .to_blob()?;
+
+        Ok((metadata.concealed(), Some(sealed)))
+    }
+
+    /// `open`, keeping the id of a row that fails.
+    fn try_open(&self, row: RecordRow) -> Result<RecordRow, UnreadableRecord> {
+        let id = row.id;
+        self.open(row)
+            .map_err(|error| UnreadableRecord { id, error })
+    }
+
+    /// Decrypts the sealed metadata, if any, and authenticates the row.
+    /// The plaintext columns of a row in privacy mode must match what it would have stored.
+    fn open(&self, mut row: RecordRow) -> Result<RecordRow, IterateError> {
+        if let Some(sealed) = &row.sealed_metadata {
+            let envelope = CryptoEnvelope::from_blob(sealed)?;
+            let plaintext = Zeroizing::new(decrypt(
+                self.record_metadata_key,
+                &envelope,
+                &sealed_metadata_aad(&row.id),
+            )?);
+            let metadata: RecordMetadata = postcard::from_bytes(&plaintext)
+                .map_err(|e| IterateError::PostCardSerializationFailed(e.to_string()))?;
+
+            if RecordMetadata::of(&row) != metadata.concealed() {
+                error!("Record {} has altered plaintext metadata", row.id);
+                return Err(IterateError::RecordTampered);
+            }
+            metadata.apply(&mut row);
+        }
+
+        self.verify(&row)?;
+        Ok(row)
+    }
+
+    /// Runs `write` in its own transaction, unless the caller already opened one.
+    fn atomically<T>(
+        &self,
+        write: impl FnOnce() -> Result<T, IterateError>,
+    ) -> Result<T, IterateError> {
+        if !self.conn.is_autocommit() {
+            return write();
+        }
+
+        let tx = self.conn.unchecked_transaction()?;
+        let value = write()?;
+        tx.commit()?;
+        Ok(value)
+    }
+
+    fn mac(&self, row: &RecordRow) -> Result<Vec<u8>, IterateError> {
+        Ok(compute_mac(self.meta_key, METADATA_MAC_DOMAIN, &row.metadata_bytes()?)?.to_vec())
+    }
+
+    fn verify(&self, row: &RecordRow) -> Result<(), IterateError> {
+        let authentic = match &row.metadata_mac {
+            Some(mac) => verify_mac(
+                self.meta_key,
+                METADATA_MAC_DOMAIN,
+                &row.metadata_bytes()?,
+                mac,
+            )?,
+            None => self.legacy_rows_allowed()?,
+        };
+
+        if !authentic {
+            error!("Record {} failed metadata authentication", row.id);
+            return Err(IterateError::RecordTampered);
+        }
+        Ok(())
+    }
+
+    fn current_key_epoch(&self) -> Result<i64, IterateError> {
+        Ok(MetadataRepository::new(self.conn)
+            .get_i64("key_epoch")?
+            .unwrap_or(0))
+    }
+}
+
+fn map_row(row: &Row) -> rusqlite::Result<RecordRow> {
+    Ok(RecordRow {
+        id: Uuid::from_slice(&row.get::<_, Vec<u8>>("record_id")?).unwrap_or_default(),
+        encrypted_content: row.get("encrypted_content")?,
+        wrapped_data_key: row.get("wrapped_data_key")?,
+        key_epoch: row.get("key_epoch")?,
+        sentiment_score: row.get("sentiment_score")?,
+        is_summarized: row.get::<_, i32>("is_summarized")? != 0,
+        is_summary_record: row.get::<_, i32>("is_summary_record")? != 0,
+        is_archived: row.get::<_, i32>("is_archived")? != 0,
+        is_deleted: row.get::<_, i32>("is_deleted")? != 0,
+        created_at_utc: row.get("created_at_utc")?,
+        last_modified_at_utc: row.get("last_modified_at_utc")?,
+        deleted_at_utc: row.get("deleted_at_utc")?,
+        metadata_mac: row.get("metadata_mac")?,
+        sealed_metadata: row.get("sealed_metadata")?,
+    })
+}
+
+fn sealed_metadata_aad(id: &Uuid) -> Vec<u8> {
+    let mut aad = SEALED_METADATA_AAD.to_vec();
+    aad.extend_from_slice(id.as_bytes());
+    aad
+}
+
+/// Start of the UTC month containing `timestamp`.
+fn month_bucket(timestamp: i64) -> i64 {
+    DateTime::from_timestamp(timestamp, 0)
+        .and_then(|time| time.date_naive().with_day(1))
+        .and_then(|day| day.and_hms_opt(0, 0, 0))
+        .map(|start| start.and_utc().timestamp())
+        .unwrap_or(0)
+}
+
+/// Moves committed pages from the WAL into the database and truncates the WAL,
+/// so deleted rows do not survive there.
+pub fn checkpoint_wal(conn: &Connection) -> Result<(), IterateError> {
+    conn.query_row("PRAGMA wal_checkpoint(TRUNCATE);", [], |_| Ok(()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::open_database;
+    use crate::database::testing::{TempJournal, service_keys};
+    use rand::rngs::OsRng;
+
+    fn stored_mac(conn: &Connection, id: &Uuid) -> Option<Vec<u8>> {
+        conn.query_row(
+            "SELECT metadata_mac FROM record WHERE record_id = ?",
+            params![id.as_bytes()],
+            |row| row.get(0),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn authenticates_rows_left_behind_by_the_migration() {
+        let journal = TempJournal::in_temp_dir();
+        let conn = open_database(journal.0.clone()).unwrap();
+        let keys = service_keys();
+        let records = RecordRepository::new(&conn, &keys);
+
+        // A row written before metadata MACs existed.
+        let id = Uuid::now_v7();
+        conn.execute(
+            "INSERT INTO record (record_id, encrypted_content, is_summarized, is_summary_record,
+                is_archived, is_deleted, created_at_utc, last_modified_at_utc)
+             VALUES (?, x'00', 0, 0, 0, 0, 1, 1)",
+            params![id.as_bytes()],
+        )
+        .unwrap();
+        assert!(records.get_record(id).is_ok());
+
+        assert_eq!(records.authenticate_legacy_rows().unwrap(), 1);
+        assert!(stored_mac(&conn, &id).is_some());
+        assert!(records.get_record(id).is_ok());
+    }
+
+    #[test]
+    fn rejects_rows_stripped_of_their_mac_after_the_upgrade() {
+        let journal = TempJournal::in_temp_dir();
+        let conn = open_database(journal.0.clone()).unwrap();
+        let keys = service_keys();
+        let records = RecordRepository::new(&conn, &keys);
+        records.authenticate_legacy_rows().unwrap();
+
+        let id = Uuid::now_v7();
+        records
+            .insert(&mut OsRng, &id, b"content", b"wrapped key", None)
+            .unwrap();
+
+        // Backdate the record, and pose as a journal the migration just upgraded.
+        conn.execute_batch(
+            "UPDATE record SET metadata_mac = NULL, created_at_utc = 1;
+             INSERT INTO metadata (key, value) VALUES ('record_macs_pending', '1');",
+        )
+        .unwrap();
+
+        assert_eq!(records.authenticate_legacy_rows().unwrap(), 0);
+        assert!(stored_mac(&conn, &id).is_none());
+        assert!(matches!(
+            records.get_record(id),
+            Err(IterateError::RecordTampered)
+        ));
+    }
+
+    #[test]
+    fn destroys_the_data_key_with_the_record() {
+        let journal = TempJournal::in_temp_dir();
+        let conn = open_database(journal.0.clone()).unwrap();
+        let keys = service_keys();
+        let records = RecordRepository::new(&conn, &keys);
+        let stored_keys = |id: &Uuid| -> i64 {
+            conn.query_row(
+                "SELECT COUNT(*) FROM record_key WHERE record_id = ?",
+                params![id.as_bytes()],
+                |row| row.get(0),
+            )
+            .unwrap()
+        };
+
+        let id = Uuid::now_v7();
+        records
+            .insert(&mut OsRng, &id, b"content", b"wrapped key", None)
+            .unwrap();
+        records
+            .update(&mut OsRng, &id, b"new content", b"new wrapped key")
+            .unwrap();
+        assert_eq!(stored_keys(&id), 1);
+        assert_eq!(
+            records.get_record(id).unwrap().wrapped_data_key.as_deref(),
+            Some(b"new wrapped key".as_slice())
+        );
+
+        records.delete_permanently(&id).unwrap();
+        assert_eq!(stored_keys(&id), 0);
+        assert!(matches!(
+            records.get_record(id),
+            Err(IterateError::RecordNotFound)
+        ));
+    }
+
+    #[test]
+    fn lists_the_newest_entries_and_reports_unreadable_ones() {
+        let journal = TempJournal::in_temp_dir();
+        let conn = open_database(journal.0.clone()).unwrap();
+        let keys = service_keys();
+        let records = RecordRepository::new(&conn, &keys);
+
+        // Three entries, with two newer summaries that must not use up the limit.
+        let mut entries = Vec::new();
+        for created_at_utc in 1..=5 {
+            let id = Uuid::now_v7();
+            records
+                .insert(&mut OsRng, &id, b"content", b"wrapped key", None)
+                .unwrap();
+            let mut row = records.get_record(id).unwrap();
+            row.created_at_utc = created_at_utc;
+            row.is_summary_record = created_at_utc > 3;
+            records.write_existing(&mut OsRng, &row).unwrap();
+            if !row.is_summary_record {
+                entries.push(id);
+            }
+        }
+        let ids = |rows: Vec<Result<RecordRow, UnreadableRecord>>| -> Vec<Uuid> {
+            rows.into_iter()
+                .map(|row| row.map_or_else(|unreadable| unreadable.id, |row| row.id))
+                .collect()
+        };
+
+        assert_eq!(
+            ids(records.fetch_latest(2).unwrap()),
+            [entries[2], entries[1]]
+        );
+        records.set_privacy_mode(&mut OsRng, true).unwrap();
+        assert_eq!(
+            ids(records.fetch_latest(2).unwrap()),
+            [entries[2], entries[1]]
+        );
+        records.set_privacy_mode(&mut OsRng, false).unwrap();
+
+        conn.execute(
+            "UPDATE record SET metadata_mac = x'00' WHERE record_id = ?",
+            params![entries[2].as_bytes()],
+        )
+        .unwrap();
+        let latest = records.fetch_latest(2).unwrap();
+        assert!(matches!(
+            &latest[0],
+            Err(UnreadableRecord {
+                error: IterateError::RecordTampered,
+                ..
+            })
+        ));
+        assert_eq!(ids(latest), [entries[2], entries[1]]);
+    }
 }
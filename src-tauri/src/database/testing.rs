@@ -0,0 +1,30 @@
+use crate::crypto::kdf::derive_service_keys;
+use crate::crypto::servicekeys::ServiceKeys;
+use std::path::PathBuf;
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+/// A journal path in the temp directory. The journal and its WAL files are removed
+/// when the test ends, pass or fail.
+pub struct TempJournal(pub PathBuf);
+
+impl TempJournal {
+    pub fn in_temp_dir() -> Self {
+        TempJournal(std::env::temp_dir().join(format!("iterate-{}.db", Uuid::now_v7())))
+    }
+}
+
+impl Drop for TempJournal {
+    fn drop(&mut self) {
+        for suffix in ["", "-wal", "-shm", ".lock"] {
+            let mut path = self.0.clone().into_os_string();
+            path.push(suffix);
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Service keys for tests that do not need a key store, which would run Argon2.
+pub fn service_keys() -> ServiceKeys {
+    derive_service_keys(&Zeroizing::new([7u8; 32])).expect("service keys derive")
+}
@@ -0,0 +1,405 @@
+use crate::crypto::aead::{decrypt, encrypt_encoded};
+use crate::crypto::compression::Compression;
+use crate::crypto::cryptoenvelope::CryptoEnvelope;
+use crate::crypto::mac::compute_mac;
+use crate::crypto::padding::Padding;
+use crate::crypto::servicekeys::ServiceKeys;
+use crate::database::records::{checkpoint_wal, new_row_id};
+use crate::error::IterateError;
+use chrono::Utc;
+use rand::TryRngCore;
+use rusqlite::{Connection, ErrorCode, OptionalExtension, Row, params};
+use serde::Serialize;
+use tracing::warn;
+use uuid::Uuid;
+
+const NAME_INDEX_DOMAIN: &[u8] = b"topic-name-index-v1";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TopicRow {
+    pub id: Uuid,
+    pub name: String,
+    pub note: Option<String>,
+    pub created_at_utc: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PromptRow {
+    pub id: Uuid,
+    pub topic_id: Uuid,
+    pub text: String,
+    pub is_default: bool,
+}
+
+/// Reads and writes topics and prompts. Names, notes and prompt texts are stored
+/// encrypted with `ServiceKeys::topic`. Topic names are looked up through a blind
+/// index keyed with `ServiceKeys::blind_index`, which also keeps them unique.
+pub struct TopicRepository<'a> {
+    conn: &'a Connection,
+    keys: &'a ServiceKeys,
+}
+
+impl<'a> TopicRepository<'a> {
+    pub fn new(conn: &'a Connection, keys: &'a ServiceKeys) -> Self {
+        Self { conn, keys }
+    }
+
+    pub fn insert_topic<R: TryRngCore>(
+        &self,
+        rng: &mut R,
+        name: &str,
+        note: Option<&str>,
+    ) -> Result<Uuid, IterateError> {
+        let id = new_row_id(self.conn)?;
+        self.insert_topic_with_id(rng, &id, name, note, Utc::now().timestamp())?;
+        Ok(id)
+    }
+
+    pub fn update_topic<R: TryRngCore>(
+        &self,
+        rng: &mut R,
+        id: &Uuid,
+        name: &str,
+        note: Option<&str>,
+    ) -> Result<(), IterateError> {
+        let updated = self
+            .conn
+            .prepare_cached(
+                "UPDATE topic SET name = ?, name_index = ?, note = ? WHERE topic_id = ?",
+            )?
+            .execute(params![
+                self.seal(rng, b"topic.name", id, name)?,
+                self.name_index(name)?,
+                note.map(|note| self.seal(rng, b"topic.note", id, note))
+                    .transpose()?,
+                id.as_bytes()
+            ])
+            .map_err(duplicate_topic)?;
+        if updated == 0 {
+            return Err(IterateError::TopicNotFound);
+        }
+
+        Ok(())
+    }
+
+    pub fn delete_topic(&self, id: &Uuid) -> Result<(), IterateError> {
+        let deleted = self
+            .conn
+            .prepare_cached("DELETE FROM topic WHERE topic_id = ?")?
+            .execute(params![id.as_bytes()])?;
+        if deleted == 0 {
+            return Err(IterateError::TopicNotFound);
+        }
+
+        Ok(())
+    }
+
+    /// Finds a topic by name without decrypting every topic.
+    pub fn find_topic_by_name(&self, name: &str) -> Result<Option<TopicRow>, IterateError> {
+        let row = self
+            .conn
+            .prepare_cached("SELECT * FROM topic WHERE name_index = ?")?
+            .query_row(params![self.name_index(name)?], map_sealed_topic)
+            .optional()?;
+
+        row.map(|row| self.open_topic(row)).transpose()
+    }
+
+    pub fn list_topics(&self) -> Result<Vec<TopicRow>, IterateError> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT * FROM topic ORDER BY created_at_utc")?;
+        let rows = stmt
+            .query_map([], map_sealed_topic)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        rows.into_iter().map(|row| self.open_topic(row)).collect()
+    }
+
+    pub fn insert_prompt<R: TryRngCore>(
+        &self,
+        rng: &mut R,
+        topic_id: &Uuid,
+        text: &str,
+        is_default: bool,
+    ) -> Result<Uuid, IterateError> {
+        let id = new_row_id(self.conn)?;
+        self.insert_prompt_with_id(rng, &id, topic_id, text, is_default)?;
+        Ok(id)
+    }
+
+    pub fn list_prompts(&self, topic_id: &Uuid) -> Result<Vec<PromptRow>, IterateError> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT * FROM prompt WHERE topic_id = ?")?;
+        let rows = stmt
+            .query_map(params![topic_id.as_bytes()], |row| {
+                Ok((
+                    uuid_column(row, "prompt_id")?,
+                    uuid_column(row, "topic_id")?,
+                    row.get::<_, Vec<u8>>("prompt_text")?,
+                    row.get::<_, i32>("is_default")? != 0,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        rows.into_iter()
+            .map(|(id, topic_id, text, is_default)| {
+                Ok(PromptRow {
+                    text: self.open(b"prompt.text", &id, &text)?,
+                    id,
+                    topic_id,
+                    is_default,
+                })
+            })
+            .collect()
+    }
+
+    /// Re-encrypts every topic and prompt under `new_keys` and recomputes the name index
+    /// with its blind index key. Used by the key rotation, inside its last transaction.
+    pub fn reencrypt_all<R: TryRngCore>(
+        &self,
+        rng: &mut R,
+        new_keys: &ServiceKeys,
+    ) -> Result<usize, IterateError> {
+        let rotated = TopicRepository::new(self.conn, new_keys);
+        let mut count = 0;
+
+        for topic in self.list_topics()? {
+            for prompt in self.list_prompts(&topic.id)? {
+                rotated.update_prompt_text(rng, &prompt.id, &prompt.text)?;
+                count += 1;
+            }
+            rotated.update_topic(rng, &topic.id, &topic.name, topic.note.as_deref())?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Encrypts topics and prompts that 008_encrypted_topics.sql moved aside, restores
+    /// the record tags and drops the plaintext tables. Names that collide once
+    /// normalized get a suffix. The file is vacuumed afterwards, so no free page
+    /// keeps the plaintext.
+    pub fn encrypt_legacy_rows<R: TryRngCore>(&self, rng: &mut R) -> Result<usize, IterateError> {
+        let pending: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT name FROM sqlite_master WHERE type = 'table' AND name = 'legacy_topic'",
+                [],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if pending.is_none() {
+            return Ok(0);
+        }
+
+        let tx = self.conn.unchecked_transaction()?;
+
+        let mut stmt = self.conn.prepare("SELECT * FROM legacy_topic")?;
+        let topics = stmt
+            .query_map([], |row| {
+                Ok((
+                    uuid_column(row, "topic_id")?,
+                    row.get::<_, String>("name")?,
+                    row.get::<_, Option<String>>("note")?,
+                    row.get::<_, i64>("created_at_utc")?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        for (id, name, note, created_at_utc) in &topics {
+            let mut candidate = name.clone();
+            let mut suffix = 1;
+            while self.find_topic_by_name(&candidate)?.is_some() {
+                suffix += 1;
+                candidate = format!("{} ({})", name, suffix);
+            }
+            if suffix > 1 {
+                warn!("Topic {} renamed to avoid a duplicate name", id);
+            }
+            self.insert_topic_with_id(rng, id, &candidate, note.as_deref(), *created_at_utc)?;
+        }
+
+        let mut stmt = self.conn.prepare("SELECT * FROM legacy_prompt")?;
+        let prompts = stmt
+            .query_map([], |row| {
+                Ok((
+                    uuid_column(row, "prompt_id")?,
+                    uuid_column(row, "topic_id")?,
+                    row.get::<_, String>("prompt_text")?,
+                    row.get::<_, i32>("is_default")? != 0,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        for (id, topic_id, text, is_default) in &prompts {
+            self.insert_prompt_with_id(rng, id, topic_id, text, *is_default)?;
+        }
+
+        tx.execute_batch(
+            "INSERT INTO record_topic (record_id, topic_id)
+                SELECT record_id, topic_id FROM legacy_record_topic
+                WHERE record_id IN (SELECT record_id FROM record)
+                  AND topic_id IN (SELECT topic_id FROM topic);
+             DROP TABLE legacy_record_topic;
+             DROP TABLE legacy_prompt;
+             DROP TABLE legacy_topic;",
+        )?;
+        tx.commit()?;
+
+        self.conn.execute_batch("VACUUM")?;
+        checkpoint_wal(self.conn)?;
+
+        Ok(topics.len() + prompts.len())
+    }
+
+    fn insert_topic_with_id<R: TryRngCore>(
+        &self,
+        rng: &mut R,
+        id: &Uuid,
+        name: &str,
+        note: Option<&str>,
+        created_at_utc: i64,
+    ) -> Result<(), IterateError> {
+        self.conn
+            .prepare_cached(
+                "INSERT INTO topic (topic_id, name, name_index, note, created_at_utc)
+                 VALUES (?, ?, ?, ?, ?)",
+            )?
+            .execute(params![
+                id.as_bytes(),
+                self.seal(rng, b"topic.name", id, name)?,
+                self.name_index(name)?,
+                note.map(|note| self.seal(rng, b"topic.note", id, note))
+                    .transpose()?,
+                created_at_utc
+            ])
+            .map_err(duplicate_topic)?;
+
+        Ok(())
+    }
+
+    fn insert_prompt_with_id<R: TryRngCore>(
+        &self,
+        rng: &mut R,
+        id: &Uuid,
+        topic_id: &Uuid,
+        text: &str,
+        is_default: bool,
+    ) -> Result<(), IterateError> {
+        self.conn
+            .prepare_cached(
+                "INSERT INTO prompt (prompt_id, topic_id, prompt_text, is_default)
+             VALUES (?, ?, ?, ?)",
+            )?
+            .execute(params![
+                id.as_bytes(),
+                topic_id.as_bytes(),
+                self.seal(rng, b"prompt.text", id, text)?,
+                is_default as i32
+            ])?;
+
+        Ok(())
+    }
+
+    fn update_prompt_text<R: TryRngCore>(
+        &self,
+        rng: &mut R,
+        id: &Uuid,
+        text: &str,
+    ) -> Result<(), IterateError> {
+        self.conn
+            .prepare_cached("UPDATE prompt SET prompt_text = ? WHERE prompt_id = ?")?
+            .execute(params![
+                self.seal(rng, b"prompt.text", id, text)?,
+                id.as_bytes()
+            ])?;
+
+        Ok(())
+    }
+
+    fn open_topic(&self, row: SealedTopic) -> Result<TopicRow, IterateError> {
+        Ok(TopicRow {
+            name: self.open(b"topic.name", &row.id, &row.name)?,
+            note: row
+                .note
+                .map(|note| self.open(b"topic.note", &row.id, &note))
+                .transpose()?,
+            id: row.id,
+            created_at_utc: row.created_at_utc,
+        })
+    }
+
+    /// Blind index of a topic name. Names are compared trimmed and case-insensitively.
+    fn name_index(&self, name: &str) -> Result<Vec<u8>, IterateError> {
+        let normalized = name.trim().to_lowercase();
+        Ok(compute_mac(
+            &self.keys.blind_index,
+            NAME_INDEX_DOMAIN,
+            normalized.as_bytes(),
+        )?
+        .to_vec())
+    }
+
+    /// Encrypts a column value. The AAD binds it to its column and row, and the padding
+    /// hides the length of short names.
+    fn seal<R: TryRngCore>(
+        &self,
+        rng: &mut R,
+        column: &[u8],
+        id: &Uuid,
+        value: &str,
+    ) -> Result<Vec<u8>, IterateError> {
+        encrypt_encoded(
+            rng,
+            &self.keys.topic,
+            value.as_bytes(),
+            &column_aad(column, id),
+            Compression::None,
+            Padding::PowerOfTwo,
+        )?
+        .to_blob()
+    }
+
+    fn open(&self, column: &[u8], id: &Uuid, blob: &[u8]) -> Result<String, IterateError> {
+        let envelope = CryptoEnvelope::from_blob(blob)?;
+        let plaintext = decrypt(&self.keys.topic, &envelope, &column_aad(column, id))?;
+
+        String::from_utf8(plaintext).map_err(|e| IterateError::DecryptionFailed(e.to_string()))
+    }
+}
+
+struct SealedTopic {
+    id: Uuid,
+    name: Vec<u8>,
+    note: Option<Vec<u8>>,
+    created_at_utc: i64,
+}
+
+fn map_sealed_topic(row: &Row) -> rusqlite::Result<SealedTopic> {
+    Ok(SealedTopic {
+        id: uuid_column(row, "topic_id")?,
+        name: row.get("name")?,
+        note: row.get("note")?,
+        created_at_utc: row.get("created_at_utc")?,
+    })
+}
+
+fn uuid_column(row: &Row, column: &str) -> rusqlite::Result<Uuid> {
+    Ok(Uuid::from_slice(&row.get::<_, Vec<u8>>(column)?).unwrap_or_default())
+}
+
+fn column_aad(column: &[u8], id: &Uuid) -> Vec<u8> {
+    let mut aad = column.to_vec();
+    aad.extend_from_slice(id.as_bytes());
+    aad
+}
+
+/// Apart from the random primary key, the blind index is the only UNIQUE column,
+/// so a constraint violation means a duplicate name.
+fn duplicate_topic(e: rusqlite::Error) -> IterateError {
+    match e.sqlite_error_code() {
+        Some(ErrorCode::ConstraintViolation) => IterateError::DuplicateTopic,
+        _ => IterateError::Database(e),
+    }
+}
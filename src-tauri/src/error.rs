@@ -1,5 +1,7 @@
 use thiserror::Error;
 
+use crate::services::journallock::LockHolder;
+
 /// Central error type for the Iterate application.
 #[derive(Error, Debug)]
 pub enum IterateError {
@@ -7,15 +9,39 @@ pub enum IterateError {
     #[error("integrity table is missing or malformed")]
     MissingIntegrityRecord,
 
+    #[error("the journal already has a key store")]
+    KeyStoreExists,
+
     #[error("database operation failed")]
     Database(#[from] rusqlite::Error),
 
     #[error("database integrity failed")]
     DatabaseIntegrity,
 
+    #[error("journal schema version {found} is newer than the supported {supported}")]
+    UnsupportedSchemaVersion { found: u32, supported: u32 },
+
+    #[error("the journal could not be upgraded and was restored from its backup: {0}")]
+    MigrationFailed(String),
+
+    #[error("the journal was written by a newer version and is open read-only")]
+    ReadOnlyJournal,
+
     #[error("Record was not found in database")]
     RecordNotFound,
 
+    #[error("a topic with this name already exists")]
+    DuplicateTopic,
+
+    #[error("topic was not found in database")]
+    TopicNotFound,
+
+    #[error("record metadata failed authentication - possible tampering")]
+    RecordTampered,
+
+    #[error("the record chain is broken - records were removed or inserted outside the app")]
+    JournalChainBroken,
+
     // ── Cryptography ────────────────────────────────────────────────────────
     #[error("password is incorrect or journal is corrupted")]
     InvalidPassword,
@@ -26,12 +52,46 @@ pub enum IterateError {
     #[error("key derivation failed (Argon2)")]
     KeyDerivationFailed,
 
+    #[error("not enough memory for the key derivation parameters")]
+    InsufficientKdfMemory,
+
     #[error("HKDF expansion failed")]
     HkdfExpansionFailed,
 
+    #[error("this journal requires its keyfile to unlock")]
+    KeyfileRequired,
+
+    #[error("the keyfile does not belong to this journal")]
+    KeyfileMismatch,
+
+    #[error("the keyfile could not be found")]
+    KeyfileNotFound,
+
+    #[error("recovery phrase is not a valid word list")]
+    InvalidRecoveryPhrase,
+
+    #[error("key slot was not found")]
+    KeySlotNotFound,
+
+    #[error("the last password slot of a journal cannot be removed")]
+    LastKeySlot,
+
     #[error("decryption operation failed")]
     DecryptionFailed(String),
 
+    #[error("decompressed content exceeds the size limit")]
+    DecompressionLimitExceeded,
+
+    // ── Session ─────────────────────────────────────────────────────────────
+    #[error("the journal session cannot go from {from} to {to}")]
+    InvalidSessionTransition {
+        from: &'static str,
+        to: &'static str,
+    },
+
+    #[error("the journal is in use by process {} on {}", .0.pid, .0.hostname)]
+    JournalInUse(LockHolder),
+
     // ── IO / Filesystem ─────────────────────────────────────────────────────
     #[error("I/O error while accessing journal file: {0}")]
     Io(#[from] std::io::Error),
@@ -6,7 +6,8 @@ mod services;
 mod state;
 
 use parking_lot::Mutex;
-use state::AppState;
+use state::{AppState, Session};
+use std::time::SystemTime;
 use tauri::Manager;
 
 use crate::state::AppConfig;
@@ -16,10 +17,13 @@ pub fn run() {
     tauri::Builder::default()
         .setup(|app| {
             app.manage(AppState {
-                db_path: Mutex::new(None),
-                serivce_keys: Mutex::new(None),
                 app_config: Mutex::new(AppConfig::default()),
+                session: Mutex::new(Session::NoJournal),
+                journal_lock: Mutex::new(None),
+                last_activity: Mutex::new(SystemTime::now()),
             });
+            commands::session::spawn_idle_watcher(app.handle().clone());
+            crypto::calibration::calibrate_in_background();
             Ok(())
         })
         .plugin(tauri_plugin_dialog::init())
@@ -29,8 +33,37 @@ pub fn run() {
             commands::journal::open_journal,
             commands::journal::create_journal,
             commands::journal::unlock_journal,
+            commands::journal::unlock_journal_with_recovery_phrase,
+            commands::journal::change_password,
+            commands::journal::add_recovery_phrase,
+            commands::journal::revoke_recovery_phrases,
+            commands::journal::list_unlock_slots,
+            commands::journal::add_unlock_slot,
+            commands::journal::remove_unlock_slot,
+            commands::journal::rotate_content_key,
+            commands::journal::verify_journal_chain,
+            commands::journal::check_journal_integrity,
+            commands::journal::is_journal_read_only,
+            commands::journal::get_padding_policy,
+            commands::journal::set_padding_policy,
+            commands::journal::get_compression_policy,
+            commands::journal::set_compression_policy,
+            commands::journal::get_privacy_mode,
+            commands::journal::set_privacy_mode,
+            commands::session::lock_journal,
+            commands::session::record_activity,
             commands::record::save_journal_entry,
+            commands::record::delete_journal_entry_permanently,
+            commands::record::get_journal_entry,
+            commands::record::list_journal_entries,
+            commands::topic::create_topic,
+            commands::topic::update_topic,
+            commands::topic::delete_topic,
+            commands::topic::list_topics,
+            commands::topic::create_prompt,
+            commands::topic::list_prompts,
         ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(commands::session::handle_run_event);
 }
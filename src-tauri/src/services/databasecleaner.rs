@@ -1,10 +1,18 @@
+use crate::crypto::servicekeys::ServiceKeys;
+use crate::database::RecordRepository;
+use crate::database::records::checkpoint_wal;
 use crate::error::IterateError;
 use chrono::{Duration, Utc};
-use rusqlite::{Connection, params};
-use tracing::{error, info};
+use rusqlite::Connection;
+use tracing::info;
 
+/// Permanently removes soft-deleted records past their retention period.
+/// Their data keys are destroyed with them, which leaves any copy of their ciphertext
+/// in this file unreadable. Copies of the journal taken earlier still hold the keys.
+/// Only records whose metadata authenticates are purged, and each purge extends the record chain.
 pub fn purge_old_deleted_records(
     conn: &Connection,
+    keys: &ServiceKeys,
     retention_days: u64,
 ) -> Result<usize, IterateError> {
     let cutoff_time = Utc::now()
@@ -12,14 +20,17 @@ pub fn purge_old_deleted_records(
         .ok_or_else(|| IterateError::Internal("Time calculation overflow".into()))?
         .timestamp();
 
-    let deleted_count = conn.execute(
-        "DELETE FROM record 
-             WHERE is_deleted = 1 
-             AND deleted_at_utc < ?",
-        params![cutoff_time],
-    )?;
+    let records = RecordRepository::new(conn, keys);
+    let purgeable = records.fetch_deleted_before(cutoff_time)?;
+
+    let mut deleted_count = 0;
+    for row in purgeable {
+        records.delete(&row.id)?;
+        deleted_count += 1;
+    }
 
     if deleted_count > 0 {
+        checkpoint_wal(conn)?;
         info!(
             "Maintenance: Purged {} old soft-deleted records.",
             deleted_count
@@ -0,0 +1,122 @@
+use crate::error::IterateError;
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+/// Marks serialized payloads. 0xFF never occurs in UTF-8, so content starting
+/// with it cannot be a raw text record from before payloads existed.
+const PAYLOAD_MAGIC: &[u8] = b"\xFFIEP";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryFormat {
+    #[default]
+    Plain,
+    Markdown,
+}
+
+/// Everything about an entry that lives inside the ciphertext.
+/// New versions are added as variants, older ones stay readable.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum EntryPayload {
+    V1(EntryPayloadV1),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct EntryPayloadV1 {
+    pub title: Option<String>,
+    pub text: String,
+    pub format: EntryFormat,
+    /// Text of the prompt the entry answers, kept even if the prompt is deleted later.
+    pub prompt: Option<String>,
+    pub mood: Option<u8>,
+    pub word_count: u32,
+}
+
+impl EntryPayload {
+    pub fn to_bytes(&self) -> Result<Zeroizing<Vec<u8>>, IterateError> {
+        let mut bytes = Zeroizing::new(PAYLOAD_MAGIC.to_vec());
+        postcard::to_io(self, &mut *bytes)
+            .map_err(|e| IterateError::PostCardSerializationFailed(e.to_string()))?;
+
+        Ok(bytes)
+    }
+
+    /// Upgrades an older payload to the current version.
+    pub fn into_latest(self) -> EntryPayloadV1 {
+        match self {
+            EntryPayload::V1(payload) => payload,
+        }
+    }
+
+    /// Parses decrypted entry content. Content without `PAYLOAD_MAGIC` is the raw
+    /// UTF-8 text older versions stored, and is upgraded to a plain text payload.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, IterateError> {
+        match bytes.strip_prefix(PAYLOAD_MAGIC) {
+            Some(payload) => postcard::from_bytes(payload)
+                .map_err(|e| IterateError::PostCardSerializationFailed(e.to_string())),
+            None => {
+                let text = String::from_utf8(bytes.to_vec())
+                    .map_err(|e| IterateError::DecryptionFailed(e.to_string()))?;
+
+                Ok(EntryPayload::V1(EntryPayloadV1 {
+                    word_count: count_words(&text),
+                    text,
+                    ..Default::default()
+                }))
+            }
+        }
+    }
+}
+
+pub fn count_words(text: &str) -> u32 {
+    text.split_whitespace().count() as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payload_survives_a_round_trip() {
+        let payload = EntryPayload::V1(EntryPayloadV1 {
+            title: Some("Tuesday".to_string()),
+            text: "Walked to the lake.".to_string(),
+            format: EntryFormat::Markdown,
+            prompt: Some("What went well today?".to_string()),
+            mood: Some(4),
+            word_count: 4,
+        });
+
+        let parsed = EntryPayload::from_bytes(&payload.to_bytes().unwrap())
+            .unwrap()
+            .into_latest();
+        assert_eq!(parsed.title.as_deref(), Some("Tuesday"));
+        assert_eq!(parsed.text, "Walked to the lake.");
+        assert_eq!(parsed.format, EntryFormat::Markdown);
+        assert_eq!(parsed.mood, Some(4));
+    }
+
+    #[test]
+    fn raw_text_from_older_versions_becomes_a_plain_payload() {
+        let parsed = EntryPayload::from_bytes("Written before payloads existed".as_bytes())
+            .unwrap()
+            .into_latest();
+
+        assert_eq!(parsed.text, "Written before payloads existed");
+        assert_eq!(parsed.format, EntryFormat::Plain);
+        assert_eq!(parsed.word_count, 4);
+        assert!(parsed.title.is_none());
+    }
+
+    #[test]
+    fn rejects_content_that_is_neither_payload_nor_text() {
+        assert!(matches!(
+            EntryPayload::from_bytes(b"\xFFIEP\xFF\xFF\xFF"),
+            Err(IterateError::PostCardSerializationFailed(_))
+        ));
+        assert!(matches!(
+            EntryPayload::from_bytes(b"\xC3\x28"),
+            Err(IterateError::DecryptionFailed(_))
+        ));
+    }
+}
@@ -1,66 +1,599 @@
 use crate::crypto::aead::{decrypt, encrypt};
 use crate::crypto::cryptoenvelope::CryptoEnvelope;
 use crate::crypto::kdf::{KdfParams, derive_service_keys};
+use crate::crypto::recovery::RecoveryKey;
 use crate::crypto::servicekeys::ServiceKeys;
 use crate::error::IterateError;
 use rand::TryRngCore;
-use rusqlite::Connection;
-use tracing::error;
+use rusqlite::{Connection, TransactionBehavior, params};
+use serde::Serialize;
+use tracing::{error, info, warn};
 use zeroize::Zeroizing;
 
+const MASTER_KEY_WRAPPING_AAD: &[u8] = b"master-key-wrapping-v1";
+const PASSWORD_SLOT_LABEL: &str = "Password";
+const RECOVERY_SLOT_LABEL: &str = "Recovery key";
+
+/// One row of the key store: the master key wrapped by a single unlock secret.
+struct KeySlot {
+    slot_id: i64,
+    kdf_params: KdfParams,
+    wrapped_key: CryptoEnvelope,
+}
+
+/// What the user gets to see about a slot. Secrets and KDF parameters stay in the backend.
+#[derive(Debug, Serialize)]
+pub struct KeySlotInfo {
+    pub slot_id: i64,
+    pub label: String,
+    pub created_at_utc: i64,
+}
+
+/// Creates the key store of a new journal with a password slot and a recovery key slot.
+/// Returns the service keys and the recovery phrase, which must be shown to the user once.
+/// Fails with `KeyStoreExists` if the file already is a journal, whose records are
+/// encrypted under a master key the new slots would not wrap.
 pub fn initialize_key_store<R: TryRngCore>(
     rng: &mut R,
     conn: &mut Connection,
     password: &[u8],
-) -> Result<ServiceKeys, IterateError> {
-    let kdf_params = KdfParams::try_new(rng)?;
-    let key_encryption_key = kdf_params.derive_key_encryption_key(password)?;
+    keyfile: Option<&[u8]>,
+) -> Result<(ServiceKeys, Zeroizing<String>), IterateError> {
     let mut master_key_bytes = [0u8; 32];
     rng.try_fill_bytes(&mut master_key_bytes).map_err(|e| {
         error!("RngError: {}", e);
         IterateError::SystemRngFailure
     })?;
     let master_key = Zeroizing::new(master_key_bytes);
-    let envelope = encrypt(
+    let recovery_key = RecoveryKey::generate(rng)?;
+
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let has_slots: bool = tx.query_row("SELECT EXISTS(SELECT 1 FROM key_store)", [], |row| {
+        row.get(0)
+    })?;
+    if has_slots {
+        return Err(IterateError::KeyStoreExists);
+    }
+
+    let password_params = KdfParams::try_new(rng, keyfile)?;
+    insert_slot(
         rng,
-        &key_encryption_key,
-        master_key.as_ref(),
-        b"master-key-wrapping-v1",
+        &tx,
+        PASSWORD_SLOT_LABEL,
+        &password_params,
+        password,
+        keyfile,
+        &master_key,
+    )?;
+    let recovery_params = KdfParams::try_new_recovery(rng)?;
+    insert_slot(
+        rng,
+        &tx,
+        RECOVERY_SLOT_LABEL,
+        &recovery_params,
+        recovery_key.as_bytes(),
+        None,
+        &master_key,
+    )?;
+    tx.commit()?;
+
+    Ok((derive_service_keys(&master_key)?, recovery_key.to_phrase()?))
+}
+
+/// Unlocks the master key with the password and, for slots that require one, the keyfile digest.
+/// Journals whose KDF parameters fall below the current policy are re-wrapped
+/// with stronger parameters on the way; a failed upgrade does not block the unlock.
+pub fn verify_password<R: TryRngCore>(
+    rng: &mut R,
+    conn: &mut Connection,
+    password: &[u8],
+    keyfile: Option<&[u8]>,
+) -> Result<ServiceKeys, IterateError> {
+    let (slot, master_key) =
+        unlock_any_slot(conn, password, keyfile, KdfParams::is_password_based)?;
+
+    if slot.kdf_params.is_weaker_than_policy() {
+        match upgrade_kdf_params(rng, conn, &slot, password, keyfile, &master_key) {
+            Ok(()) => info!("KDF parameters upgraded to the current policy"),
+            Err(e) => warn!("KDF parameter upgrade failed: {}", e),
+        }
+    }
+
+    derive_service_keys(&master_key)
+}
+
+/// Unlocks the master key with a recovery phrase instead of the password.
+pub fn verify_recovery_phrase(
+    conn: &Connection,
+    phrase: &str,
+) -> Result<ServiceKeys, IterateError> {
+    let recovery_key = RecoveryKey::from_phrase(phrase)?;
+    let (_, master_key) = unlock_any_slot(conn, recovery_key.as_bytes(), None, |params| {
+        !params.is_password_based()
+    })
+    .map_err(|e| match e {
+        IterateError::InvalidPassword => IterateError::InvalidRecoveryPhrase,
+        e => e,
+    })?;
+
+    derive_service_keys(&master_key)
+}
+
+/// Replaces the password of the journal.
+/// Only the wrapping of the master key changes, so record ciphertext stays untouched.
+/// A slot that required a keyfile keeps requiring the same keyfile.
+pub fn change_password<R: TryRngCore>(
+    rng: &mut R,
+    conn: &mut Connection,
+    old_password: &[u8],
+    new_password: &[u8],
+    keyfile: Option<&[u8]>,
+) -> Result<(), IterateError> {
+    let tx = conn.transaction()?;
+
+    let (slot, master_key) =
+        unlock_any_slot(&tx, old_password, keyfile, KdfParams::is_password_based)?;
+    let keyfile = keyfile.filter(|_| slot.kdf_params.requires_keyfile());
+    let kdf_params = KdfParams::try_new(rng, keyfile)?;
+    let (kdf_blob, wrapped_key_blob) =
+        wrap_master_key(rng, &kdf_params, new_password, keyfile, &master_key)?;
+    store_wrapped_key(&tx, slot.slot_id, &kdf_blob, &wrapped_key_blob)?;
+
+    tx.commit()?;
+    Ok(())
+}
+
+/// Wraps the master key with a newly generated recovery key and returns its phrase.
+/// The password proves that the caller may access the master key.
+pub fn add_recovery_key<R: TryRngCore>(
+    rng: &mut R,
+    conn: &Connection,
+    password: &[u8],
+    keyfile: Option<&[u8]>,
+) -> Result<Zeroizing<String>, IterateError> {
+    let (_, master_key) = unlock_any_slot(conn, password, keyfile, KdfParams::is_password_based)?;
+    let recovery_key = RecoveryKey::generate(rng)?;
+    let recovery_params = KdfParams::try_new_recovery(rng)?;
+    insert_slot(
+        rng,
+        conn,
+        RECOVERY_SLOT_LABEL,
+        &recovery_params,
+        recovery_key.as_bytes(),
+        None,
+        &master_key,
+    )?;
+
+    recovery_key.to_phrase()
+}
+
+pub fn list_key_slots(conn: &Connection) -> Result<Vec<KeySlotInfo>, IterateError> {
+    let mut stmt =
+        conn.prepare("SELECT slot_id, label, created_at_utc FROM key_store ORDER BY slot_id")?;
+    let rows = stmt.query_map([], |row| {
+        Ok(KeySlotInfo {
+            slot_id: row.get(0)?,
+            label: row.get(1)?,
+            created_at_utc: row.get(2)?,
+        })
+    })?;
+
+    let mut slots = Vec::new();
+    for row in rows {
+        slots.push(row?);
+    }
+    Ok(slots)
+}
+
+/// Adds a labelled slot that unlocks the journal with `new_secret`,
+/// e.g. a second password or the contents of a keyfile.
+/// With `new_keyfile` the slot needs both `new_secret` and that keyfile.
+pub fn add_key_slot<R: TryRngCore>(
+    rng: &mut R,
+    conn: &Connection,
+    current_password: &[u8],
+    current_keyfile: Option<&[u8]>,
+    label: &str,
+    new_secret: &[u8],
+    new_keyfile: Option<&[u8]>,
+) -> Result<(), IterateError> {
+    let (_, master_key) = unlock_any_slot(
+        conn,
+        current_password,
+        current_keyfile,
+        KdfParams::is_password_based,
+    )?;
+    let kdf_params = KdfParams::try_new(rng, new_keyfile)?;
+
+    insert_slot(
+        rng,
+        conn,
+        label,
+        &kdf_params,
+        new_secret,
+        new_keyfile,
+        &master_key,
+    )
+}
+
+/// Removes a slot. The last slot that accepts a password is never removed,
+/// otherwise the journal could no longer be unlocked or managed.
+pub fn remove_key_slot(conn: &mut Connection, slot_id: i64) -> Result<(), IterateError> {
+    let tx = conn.transaction()?;
+
+    let slots = load_slots(&tx)?;
+    let slot = slots
+        .iter()
+        .find(|slot| slot.slot_id == slot_id)
+        .ok_or(IterateError::KeySlotNotFound)?;
+
+    let password_slots = slots
+        .iter()
+        .filter(|slot| slot.kdf_params.is_password_based())
+        .count();
+    if slot.kdf_params.is_password_based() && password_slots <= 1 {
+        return Err(IterateError::LastKeySlot);
+    }
+
+    tx.execute("DELETE FROM key_store WHERE slot_id = ?", params![slot_id])?;
+
+    tx.commit()?;
+    Ok(())
+}
+
+/// Removes every recovery key slot. Password slots are kept.
+pub fn revoke_recovery_keys(conn: &mut Connection) -> Result<usize, IterateError> {
+    let tx = conn.transaction()?;
+
+    let mut revoked = 0;
+    for slot in load_slots(&tx)? {
+        if !slot.kdf_params.is_password_based() {
+            revoked += tx.execute(
+                "DELETE FROM key_store WHERE slot_id = ?",
+                params![slot.slot_id],
+            )?;
+        }
+    }
+
+    tx.commit()?;
+    Ok(revoked)
+}
+
+/// The slot a secret opened, together with the master key it wraps.
+pub struct UnlockedSlot {
+    pub slot_id: i64,
+    pub requires_keyfile: bool,
+    pub master_key: Zeroizing<[u8; 32]>,
+}
+
+/// Opens the master key itself rather than the derived service keys.
+/// Only operations that replace the master key need this.
+pub fn unlock_master_key(
+    conn: &Connection,
+    password: &[u8],
+    keyfile: Option<&[u8]>,
+) -> Result<UnlockedSlot, IterateError> {
+    let (slot, master_key) =
+        unlock_any_slot(conn, password, keyfile, KdfParams::is_password_based)?;
+
+    Ok(UnlockedSlot {
+        slot_id: slot.slot_id,
+        requires_keyfile: slot.kdf_params.requires_keyfile(),
+        master_key,
+    })
+}
+
+/// What replacing the master key did to the key store.
+pub struct KeyReplacement {
+    pub recovery_phrase: Zeroizing<String>,
+    /// Slots whose secrets were unknown, and which no longer unlock the journal.
+    pub revoked_slots: Vec<KeySlotInfo>,
+}
+
+/// Wraps a new master key into the key store.
+/// Only the slot whose secret is known can be re-wrapped, so every other slot is
+/// revoked and a fresh recovery key is created.
+pub fn replace_master_key<R: TryRngCore>(
+    rng: &mut R,
+    conn: &Connection,
+    slot_id: i64,
+    password: &[u8],
+    keyfile: Option<&[u8]>,
+    new_master_key: &Zeroizing<[u8; 32]>,
+) -> Result<KeyReplacement, IterateError> {
+    let kdf_params = KdfParams::try_new(rng, keyfile)?;
+    let (kdf_blob, wrapped_key_blob) =
+        wrap_master_key(rng, &kdf_params, password, keyfile, new_master_key)?;
+    store_wrapped_key(conn, slot_id, &kdf_blob, &wrapped_key_blob)?;
+
+    let revoked_slots: Vec<KeySlotInfo> = list_key_slots(conn)?
+        .into_iter()
+        .filter(|slot| slot.slot_id != slot_id)
+        .collect();
+    conn.execute("DELETE FROM key_store WHERE slot_id <> ?", params![slot_id])?;
+
+    let recovery_key = RecoveryKey::generate(rng)?;
+    let recovery_params = KdfParams::try_new_recovery(rng)?;
+    insert_slot(
+        rng,
+        conn,
+        RECOVERY_SLOT_LABEL,
+        &recovery_params,
+        recovery_key.as_bytes(),
+        None,
+        new_master_key,
     )?;
-    let kdf_blob = kdf_params.to_blob()?;
-    let wrapped_key_blob = envelope.to_blob()?;
+
+    Ok(KeyReplacement {
+        recovery_phrase: recovery_key.to_phrase()?,
+        revoked_slots,
+    })
+}
+
+fn upgrade_kdf_params<R: TryRngCore>(
+    rng: &mut R,
+    conn: &Connection,
+    slot: &KeySlot,
+    password: &[u8],
+    keyfile: Option<&[u8]>,
+    master_key: &Zeroizing<[u8; 32]>,
+) -> Result<(), IterateError> {
+    let kdf_params = slot.kdf_params.try_upgrade(rng)?;
+    let (kdf_blob, wrapped_key_blob) =
+        wrap_master_key(rng, &kdf_params, password, keyfile, master_key)?;
+
+    store_wrapped_key(conn, slot.slot_id, &kdf_blob, &wrapped_key_blob)
+}
+
+fn insert_slot<R: TryRngCore>(
+    rng: &mut R,
+    conn: &Connection,
+    label: &str,
+    kdf_params: &KdfParams,
+    secret: &[u8],
+    keyfile: Option<&[u8]>,
+    master_key: &Zeroizing<[u8; 32]>,
+) -> Result<(), IterateError> {
+    let (kdf_blob, wrapped_key_blob) =
+        wrap_master_key(rng, kdf_params, secret, keyfile, master_key)?;
 
     conn.execute(
-        "INSERT INTO key_store (id, kdf_params, wrapped_key, created_at_utc) 
-         VALUES (1, ?, ?, strftime('%s','now'))",
-        (kdf_blob, wrapped_key_blob),
+        "INSERT INTO key_store (label, kdf_params, wrapped_key, created_at_utc) 
+         VALUES (?, ?, ?, strftime('%s','now'))",
+        params![label, kdf_blob, wrapped_key_blob],
     )?;
 
-    derive_service_keys(&master_key)
+    Ok(())
 }
 
-pub fn verify_password(conn: &Connection, password: &[u8]) -> Result<ServiceKeys, IterateError> {
-    let (kdf_blob, wrapped_key_blob): (Vec<u8>, Vec<u8>) = conn
-        .query_row(
-            "SELECT kdf_params, wrapped_key FROM key_store WHERE id = 1",
-            [],
-            |row| Ok((row.get(0)?, row.get(1)?)),
-        )
-        .map_err(|_| IterateError::MissingIntegrityRecord)?;
+fn store_wrapped_key(
+    conn: &Connection,
+    slot_id: i64,
+    kdf_blob: &[u8],
+    wrapped_key_blob: &[u8],
+) -> Result<(), IterateError> {
+    let updated = conn.execute(
+        "UPDATE key_store SET kdf_params = ?, wrapped_key = ? WHERE slot_id = ?",
+        params![kdf_blob, wrapped_key_blob, slot_id],
+    )?;
+    if updated != 1 {
+        return Err(IterateError::MissingIntegrityRecord);
+    }
+
+    Ok(())
+}
+
+/// Derives the key encryption key from the secret and wraps the master key with it.
+/// Returns the serialized (KdfParams, CryptoEnvelope) blobs for the key store.
+fn wrap_master_key<R: TryRngCore>(
+    rng: &mut R,
+    kdf_params: &KdfParams,
+    secret: &[u8],
+    keyfile: Option<&[u8]>,
+    master_key: &Zeroizing<[u8; 32]>,
+) -> Result<(Vec<u8>, Vec<u8>), IterateError> {
+    let key_encryption_key = kdf_params.derive_key_encryption_key(secret, keyfile)?;
+    let envelope = encrypt(
+        rng,
+        &key_encryption_key,
+        master_key.as_ref(),
+        MASTER_KEY_WRAPPING_AAD,
+    )?;
+
+    Ok((kdf_params.to_blob()?, envelope.to_blob()?))
+}
+
+fn load_slots(conn: &Connection) -> Result<Vec<KeySlot>, IterateError> {
+    let mut stmt =
+        conn.prepare("SELECT slot_id, kdf_params, wrapped_key FROM key_store ORDER BY slot_id")?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, Vec<u8>>(1)?,
+            row.get::<_, Vec<u8>>(2)?,
+        ))
+    })?;
 
-    let kdf_params: KdfParams = KdfParams::from_bytes(&kdf_blob)?;
+    let mut slots = Vec::new();
+    for row in rows {
+        let (slot_id, kdf_blob, wrapped_key_blob) = row?;
+        slots.push(KeySlot {
+            slot_id,
+            kdf_params: KdfParams::from_bytes(&kdf_blob)?,
+            wrapped_key: CryptoEnvelope::from_blob(&wrapped_key_blob)?,
+        });
+    }
 
-    let key_encryption_key = kdf_params.derive_key_encryption_key(password)?;
+    if slots.is_empty() {
+        return Err(IterateError::MissingIntegrityRecord);
+    }
+    Ok(slots)
+}
+
+/// Tries the secret against every slot accepted by `accepts`.
+/// Returns the first slot that opens together with the master key.
+/// If no slot opens, a keyfile problem is reported in favour of a wrong password.
+fn unlock_any_slot(
+    conn: &Connection,
+    secret: &[u8],
+    keyfile: Option<&[u8]>,
+    accepts: impl Fn(&KdfParams) -> bool,
+) -> Result<(KeySlot, Zeroizing<[u8; 32]>), IterateError> {
+    let mut failure = IterateError::InvalidPassword;
+    for slot in load_slots(conn)? {
+        if !accepts(&slot.kdf_params) {
+            continue;
+        }
+        match unwrap_master_key(&slot, secret, keyfile) {
+            Ok(master_key) => return Ok((slot, master_key)),
+            Err(IterateError::InvalidPassword) => continue,
+            Err(e @ (IterateError::KeyfileRequired | IterateError::KeyfileMismatch)) => {
+                failure = e;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Err(failure)
+}
 
-    let envelope = CryptoEnvelope::from_blob(&wrapped_key_blob)?;
+fn unwrap_master_key(
+    slot: &KeySlot,
+    secret: &[u8],
+    keyfile: Option<&[u8]>,
+) -> Result<Zeroizing<[u8; 32]>, IterateError> {
+    let key_encryption_key = slot.kdf_params.derive_key_encryption_key(secret, keyfile)?;
 
-    let master_key_vec = decrypt(&key_encryption_key, &envelope, b"master-key-wrapping-v1")
-        .map_err(|_| IterateError::InvalidPassword)?;
+    let master_key_vec = Zeroizing::new(
+        decrypt(
+            &key_encryption_key,
+            &slot.wrapped_key,
+            MASTER_KEY_WRAPPING_AAD,
+        )
+        .map_err(|_| IterateError::InvalidPassword)?,
+    );
 
     let master_key_arr: [u8; 32] = master_key_vec
+        .as_slice()
         .try_into()
         .map_err(|_| IterateError::DecryptionFailed("Decrypted key length mismatch".into()))?;
-    let master_key = Zeroizing::new(master_key_arr);
 
-    derive_service_keys(&master_key)
+    Ok(Zeroizing::new(master_key_arr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::calibration::Argon2Cost;
+    use crate::database::open_database;
+    use crate::database::testing::TempJournal;
+    use rand::rngs::OsRng;
+
+    #[test]
+    fn creates_and_unlocks_a_journal() {
+        let journal = TempJournal::in_temp_dir();
+        let password = b"correct horse battery staple";
+
+        let mut conn = open_database(journal.0.clone()).expect("a new journal opens");
+        let (created, recovery_phrase) =
+            initialize_key_store(&mut OsRng, &mut conn, password, None)
+                .expect("key store is created");
+        drop(conn);
+
+        let mut conn = open_database(journal.0.clone()).expect("the journal reopens");
+        let unlocked =
+            verify_password(&mut OsRng, &mut conn, password, None).expect("the password unlocks");
+        assert_eq!(*unlocked.content, *created.content);
+
+        let recovered =
+            verify_recovery_phrase(&conn, &recovery_phrase).expect("the phrase unlocks");
+        assert_eq!(*recovered.content, *created.content);
+
+        assert!(matches!(
+            verify_password(&mut OsRng, &mut conn, b"wrong password", None),
+            Err(IterateError::InvalidPassword)
+        ));
+        assert!(matches!(
+            initialize_key_store(&mut OsRng, &mut conn, b"another password", None),
+            Err(IterateError::KeyStoreExists)
+        ));
+        assert_eq!(list_key_slots(&conn).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn rewraps_weak_kdf_parameters_on_unlock() {
+        let journal = TempJournal::in_temp_dir();
+        let password = b"correct horse battery staple";
+
+        let mut conn = open_database(journal.0.clone()).expect("a new journal opens");
+        let (created, _) = initialize_key_store(&mut OsRng, &mut conn, password, None)
+            .expect("key store is created");
+
+        // A slot from a release with a lower floor than today's policy.
+        let weak_cost = Argon2Cost {
+            m_cost: 8 * 1024,
+            t_cost: 1,
+            p_cost: 1,
+        };
+        let weak_params = KdfParams::try_with_cost(&mut OsRng, weak_cost, None).unwrap();
+        let (slot, master_key) =
+            unlock_any_slot(&conn, password, None, KdfParams::is_password_based).unwrap();
+        let (kdf_blob, wrapped_key_blob) =
+            wrap_master_key(&mut OsRng, &weak_params, password, None, &master_key).unwrap();
+        store_wrapped_key(&conn, slot.slot_id, &kdf_blob, &wrapped_key_blob).unwrap();
+
+        let unlocked =
+            verify_password(&mut OsRng, &mut conn, password, None).expect("the password unlocks");
+        assert_eq!(*unlocked.content, *created.content);
+
+        let (slot, _) =
+            unlock_any_slot(&conn, password, None, KdfParams::is_password_based).unwrap();
+        assert!(!slot.kdf_params.is_weaker_than_policy());
+    }
+
+    #[test]
+    fn replacing_the_master_key_reports_revoked_slots() {
+        let journal = TempJournal::in_temp_dir();
+        let password = b"correct horse battery staple";
+
+        let mut conn = open_database(journal.0.clone()).expect("a new journal opens");
+        initialize_key_store(&mut OsRng, &mut conn, password, None).expect("key store is created");
+        add_key_slot(
+            &mut OsRng,
+            &conn,
+            password,
+            None,
+            "Work laptop",
+            b"second password",
+            None,
+        )
+        .expect("the slot is added");
+
+        let unlocked = unlock_master_key(&conn, password, None).expect("the password unlocks");
+        let new_master_key = Zeroizing::new([7u8; 32]);
+        let replacement = replace_master_key(
+            &mut OsRng,
+            &conn,
+            unlocked.slot_id,
+            password,
+            None,
+            &new_master_key,
+        )
+        .expect("the master key is replaced");
+
+        let revoked: Vec<&str> = replacement
+            .revoked_slots
+            .iter()
+            .map(|slot| slot.label.as_str())
+            .collect();
+        assert_eq!(revoked, [RECOVERY_SLOT_LABEL, "Work laptop"]);
+        assert_eq!(
+            *unlock_master_key(&conn, password, None).unwrap().master_key,
+            *new_master_key
+        );
+        assert!(matches!(
+            unlock_master_key(&conn, b"second password", None),
+            Err(IterateError::InvalidPassword)
+        ));
+    }
 }
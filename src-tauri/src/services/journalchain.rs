@@ -0,0 +1,166 @@
+use crate::crypto::servicekeys::ServiceKeys;
+use crate::database::RecordChain;
+use crate::database::chain::{ChainOperation, ChainState, GENESIS_LINK};
+use crate::error::IterateError;
+use rusqlite::Connection;
+use serde::Serialize;
+use std::collections::HashMap;
+use tracing::error;
+use uuid::Uuid;
+
+/// The first inconsistency found while walking the record chain.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind")]
+pub enum ChainBreak {
+    /// The link does not follow from its predecessor, or a link before it was removed.
+    BrokenLink { sequence: i64 },
+    /// The newest link is not the stored chain head, so links were cut off the end.
+    HeadMismatch,
+    /// The chain was started, but its marker or head is gone, e.g. to restart it over tampered rows.
+    MissingChain,
+    /// The chain expects this record, but it is gone from the table.
+    MissingRecord { record_id: Uuid },
+    /// The record was inserted or changed without extending the chain.
+    UnchainedRecord { record_id: Uuid },
+}
+
+/// Walks the record chain from the first link to the head and compares the
+/// state it describes with the `record` table. Returns `None` if both agree.
+pub fn verify_journal_chain(
+    conn: &Connection,
+    keys: &ServiceKeys,
+) -> Result<Option<ChainBreak>, IterateError> {
+    let chain = RecordChain::new(conn, &keys.chain);
+    let chain_break = find_break(&chain)?;
+
+    if let Some(chain_break) = &chain_break {
+        error!("Record chain is broken: {:?}", chain_break);
+    }
+    Ok(chain_break)
+}
+
+fn find_break(chain: &RecordChain) -> Result<Option<ChainBreak>, IterateError> {
+    match chain.state()? {
+        ChainState::Pending => return Ok(None),
+        ChainState::Missing => return Ok(Some(ChainBreak::MissingChain)),
+        ChainState::Started => {}
+    }
+
+    let mut expected: HashMap<Uuid, Option<Vec<u8>>> = HashMap::new();
+    let mut previous = GENESIS_LINK.to_vec();
+
+    for (index, link) in chain.links()?.into_iter().enumerate() {
+        if link.sequence != index as i64 + 1 || !chain.verify_link(&previous, &link)? {
+            return Ok(Some(ChainBreak::BrokenLink {
+                sequence: link.sequence,
+            }));
+        }
+
+        match link.operation {
+            Some(ChainOperation::Write) => {
+                expected.insert(link.record_id, link.metadata_mac);
+            }
+            Some(ChainOperation::Delete) | None => {
+                expected.remove(&link.record_id);
+            }
+        }
+        previous = link.link_mac;
+    }
+
+    let Some(head) = chain.head()? else {
+        return Ok(Some(ChainBreak::MissingChain));
+    };
+    if head != previous {
+        return Ok(Some(ChainBreak::HeadMismatch));
+    }
+
+    for (record_id, metadata_mac) in chain.record_macs()? {
+        match expected.remove(&record_id) {
+            Some(chained_mac) if chained_mac == metadata_mac => {}
+            _ => return Ok(Some(ChainBreak::UnchainedRecord { record_id })),
+        }
+    }
+
+    Ok(expected
+        .into_keys()
+        .min()
+        .map(|record_id| ChainBreak::MissingRecord { record_id }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::testing::{TempJournal, service_keys};
+    use crate::database::{RecordRepository, open_database};
+    use rand::rngs::OsRng;
+
+    fn journal_with_record(journal: &TempJournal, keys: &ServiceKeys) -> (Connection, Uuid) {
+        let conn = open_database(journal.0.clone()).unwrap();
+        let records = RecordRepository::new(&conn, keys);
+        records.authenticate_legacy_rows().unwrap();
+
+        let id = Uuid::now_v7();
+        records
+            .insert(&mut OsRng, &id, b"content", b"wrapped key", None)
+            .unwrap();
+        (conn, id)
+    }
+
+    #[test]
+    fn reports_a_removed_chain_instead_of_restarting_it() {
+        let journal = TempJournal::in_temp_dir();
+        let keys = service_keys();
+        let (conn, _) = journal_with_record(&journal, &keys);
+        assert!(verify_journal_chain(&conn, &keys).unwrap().is_none());
+
+        conn.execute_batch(
+            "DELETE FROM record_chain;
+             DELETE FROM metadata WHERE key IN ('record_chain_head', 'record_chain_started');",
+        )
+        .unwrap();
+        assert!(matches!(
+            verify_journal_chain(&conn, &keys).unwrap(),
+            Some(ChainBreak::MissingChain)
+        ));
+
+        RecordRepository::new(&conn, &keys)
+            .authenticate_legacy_rows()
+            .unwrap();
+        assert!(
+            RecordChain::new(&conn, &keys.chain)
+                .links()
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn reports_a_forged_start_marker() {
+        let journal = TempJournal::in_temp_dir();
+        let keys = service_keys();
+        let (conn, _) = journal_with_record(&journal, &keys);
+
+        conn.execute(
+            "UPDATE metadata SET value = zeroblob(32) WHERE key = 'record_chain_started'",
+            [],
+        )
+        .unwrap();
+        assert!(matches!(
+            verify_journal_chain(&conn, &keys).unwrap(),
+            Some(ChainBreak::MissingChain)
+        ));
+    }
+
+    #[test]
+    fn reports_rows_removed_outside_the_app() {
+        let journal = TempJournal::in_temp_dir();
+        let keys = service_keys();
+        let (conn, id) = journal_with_record(&journal, &keys);
+
+        conn.execute("DELETE FROM record", []).unwrap();
+        assert!(matches!(
+            verify_journal_chain(&conn, &keys).unwrap(),
+            Some(ChainBreak::MissingRecord { record_id }) if record_id == id
+        ));
+    }
+}
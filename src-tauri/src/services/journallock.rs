@@ -0,0 +1,277 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::sync::OnceLock;
+use std::time::Duration;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::error::IterateError;
+
+/// A lock that was not refreshed for this long belongs to an instance that crashed,
+/// was killed or lost the shared folder. Holders refresh it far more often.
+const STALE_AFTER: Duration = Duration::from_secs(120);
+
+/// The instance holding a journal, shown to the user when it is in use elsewhere.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LockHolder {
+    /// Tells one acquisition apart from a later one by the same process.
+    pub lock_id: Uuid,
+    pub pid: u32,
+    pub hostname: String,
+    pub acquired_at_utc: i64,
+    pub refreshed_at_utc: i64,
+}
+
+impl LockHolder {
+    /// A lock file that could not be read or parsed.
+    fn unknown(modified_at_utc: i64) -> Self {
+        LockHolder {
+            lock_id: Uuid::nil(),
+            pid: 0,
+            hostname: "unknown".to_string(),
+            acquired_at_utc: modified_at_utc,
+            refreshed_at_utc: modified_at_utc,
+        }
+    }
+
+    /// Only asked once the OS lock is taken. A live instance on this machine would
+    /// still hold that, so a holder named after this machine is gone, on any platform.
+    fn is_stale(&self) -> bool {
+        let age = Utc::now().timestamp() - self.refreshed_at_utc;
+        age > STALE_AFTER.as_secs() as i64 || self.hostname == hostname()
+    }
+}
+
+/// Advisory single-writer lock on `<journal>.lock`. SQLite only locks single
+/// transactions, so two instances could otherwise interleave writes and purges.
+/// The file is locked through the OS, so taking it over is atomic and a crashed holder
+/// loses it with its process. It also names its owner by PID and hostname, for the
+/// user and for instances on other machines, which OS locks on network shares do not
+/// reliably reach. The lock is released when dropped.
+pub struct JournalLock {
+    file: File,
+    holder: LockHolder,
+}
+
+impl JournalLock {
+    /// Takes the lock, replacing a stale one. Fails with `JournalInUse` while another
+    /// instance holds it.
+    pub fn acquire(journal: &Path) -> Result<Self, IterateError> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(lock_path(journal))?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                // Windows does not let other processes read a locked file.
+                let holder = match read_holder(&mut file) {
+                    Ok(Some(holder)) => holder,
+                    _ => LockHolder::unknown(modified_at(&file)?),
+                };
+                return Err(IterateError::JournalInUse(holder));
+            }
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
+
+        if let Some(current) = read_holder(&mut file)? {
+            if !current.is_stale() {
+                return Err(IterateError::JournalInUse(current));
+            }
+            warn!(
+                "Replacing the stale journal lock of process {} on {}",
+                current.pid, current.hostname
+            );
+        }
+
+        let now = Utc::now().timestamp();
+        let holder = LockHolder {
+            lock_id: Uuid::now_v7(),
+            pid: process::id(),
+            hostname: hostname().to_string(),
+            acquired_at_utc: now,
+            refreshed_at_utc: now,
+        };
+        write_holder(&mut file, &holder)?;
+
+        Ok(JournalLock { file, holder })
+    }
+
+    /// Marks the lock as alive. Returns false if an instance on another machine took it
+    /// over, e.g. because this machine was suspended for longer than `STALE_AFTER`.
+    pub fn refresh(&mut self) -> Result<bool, IterateError> {
+        if !self.is_held()? {
+            return Ok(false);
+        }
+
+        self.holder.refreshed_at_utc = Utc::now().timestamp();
+        write_holder(&mut self.file, &self.holder)?;
+        Ok(true)
+    }
+
+    fn is_held(&mut self) -> Result<bool, IterateError> {
+        Ok(read_holder(&mut self.file)?
+            .is_some_and(|current| current.lock_id == self.holder.lock_id))
+    }
+}
+
+/// The file is emptied rather than removed: an instance that opened it just before
+/// could otherwise lock a file that no other instance can see any more.
+/// Closing it releases the OS lock.
+impl Drop for JournalLock {
+    fn drop(&mut self) {
+        match self.is_held() {
+            Ok(true) => {
+                if let Err(e) = self.file.set_len(0) {
+                    warn!("Releasing the journal lock failed: {}", e);
+                }
+            }
+            Ok(false) => warn!("The journal lock was taken over by another instance"),
+            Err(e) => warn!("Reading the journal lock failed: {}", e),
+        }
+    }
+}
+
+fn lock_path(journal: &Path) -> PathBuf {
+    let mut name = OsString::from(journal.as_os_str());
+    name.push(".lock");
+    PathBuf::from(name)
+}
+
+/// `None` for a released lock.
+fn read_holder(file: &mut File) -> Result<Option<LockHolder>, IterateError> {
+    let mut bytes = Vec::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_end(&mut bytes)?;
+    if bytes.is_empty() {
+        return Ok(None);
+    }
+
+    match serde_json::from_slice(&bytes) {
+        Ok(holder) => Ok(Some(holder)),
+        // E.g. one caught while it was being written.
+        Err(_) => Ok(Some(LockHolder::unknown(modified_at(file)?))),
+    }
+}
+
+/// Written in place, since the OS lock belongs to this file and not to a renamed copy.
+fn write_holder(file: &mut File, holder: &LockHolder) -> Result<(), IterateError> {
+    let bytes = serde_json::to_vec(holder)?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&bytes)?;
+    file.set_len(bytes.len() as u64)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+fn modified_at(file: &File) -> Result<i64, IterateError> {
+    let modified: DateTime<Utc> = file.metadata()?.modified()?.into();
+    Ok(modified.timestamp())
+}
+
+fn hostname() -> &'static str {
+    static HOSTNAME: OnceLock<String> = OnceLock::new();
+    HOSTNAME.get_or_init(|| {
+        std::env::var("COMPUTERNAME")
+            .ok()
+            .or_else(|| fs::read_to_string("/etc/hostname").ok())
+            .or_else(|| {
+                let output = Command::new("hostname").output().ok()?;
+                String::from_utf8(output.stdout).ok()
+            })
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| "unknown".to_string())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::testing::TempJournal;
+
+    fn holder_in(journal: &TempJournal) -> Option<LockHolder> {
+        let mut file = File::open(lock_path(&journal.0)).unwrap();
+        read_holder(&mut file).unwrap()
+    }
+
+    #[test]
+    fn holds_the_journal_until_dropped() {
+        let journal = TempJournal::in_temp_dir();
+        let mut lock = JournalLock::acquire(&journal.0).expect("the journal is free");
+
+        match JournalLock::acquire(&journal.0) {
+            Err(IterateError::JournalInUse(holder)) => {
+                assert_eq!(holder.lock_id, lock.holder.lock_id);
+                assert_eq!(holder.pid, process::id());
+            }
+            _ => panic!("the journal was locked twice"),
+        }
+        assert!(lock.refresh().unwrap());
+
+        drop(lock);
+        assert!(holder_in(&journal).is_none());
+        JournalLock::acquire(&journal.0).expect("the released journal is free");
+    }
+
+    #[test]
+    fn replaces_a_stale_lock() {
+        let journal = TempJournal::in_temp_dir();
+        let stale = LockHolder {
+            lock_id: Uuid::now_v7(),
+            pid: process::id(),
+            hostname: "elsewhere".to_string(),
+            acquired_at_utc: 0,
+            refreshed_at_utc: 0,
+        };
+        fs::write(lock_path(&journal.0), serde_json::to_vec(&stale).unwrap()).unwrap();
+
+        let lock = JournalLock::acquire(&journal.0).expect("the stale lock is replaced");
+        assert_eq!(holder_in(&journal).unwrap().lock_id, lock.holder.lock_id);
+    }
+
+    #[test]
+    fn replaces_a_fresh_lock_left_on_this_machine() {
+        let journal = TempJournal::in_temp_dir();
+        let now = Utc::now().timestamp();
+        let crashed = LockHolder {
+            lock_id: Uuid::now_v7(),
+            pid: u32::MAX,
+            hostname: hostname().to_string(),
+            acquired_at_utc: now,
+            refreshed_at_utc: now,
+        };
+        fs::write(lock_path(&journal.0), serde_json::to_vec(&crashed).unwrap()).unwrap();
+
+        let lock = JournalLock::acquire(&journal.0).expect("no instance here holds the OS lock");
+        assert_eq!(holder_in(&journal).unwrap().lock_id, lock.holder.lock_id);
+    }
+
+    #[test]
+    fn notices_a_takeover_from_another_machine() {
+        let journal = TempJournal::in_temp_dir();
+        let mut lock = JournalLock::acquire(&journal.0).unwrap();
+
+        // An instance on another machine does not see the OS lock on a network share.
+        let now = Utc::now().timestamp();
+        let other = LockHolder {
+            lock_id: Uuid::now_v7(),
+            pid: 1,
+            hostname: "elsewhere".to_string(),
+            acquired_at_utc: now,
+            refreshed_at_utc: now,
+        };
+        fs::write(lock_path(&journal.0), serde_json::to_vec(&other).unwrap()).unwrap();
+
+        assert!(!lock.refresh().unwrap());
+        drop(lock);
+        assert_eq!(holder_in(&journal).unwrap().lock_id, other.lock_id);
+    }
+}
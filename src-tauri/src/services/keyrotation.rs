@@ -0,0 +1,237 @@
+use crate::crypto::aead::{decrypt, encrypt};
+use crate::crypto::cryptoenvelope::CryptoEnvelope;
+use crate::crypto::kdf::derive_service_keys;
+use crate::crypto::servicekeys::ServiceKeys;
+use crate::database::{MetadataRepository, RecordChain, RecordRepository, TopicRepository};
+use crate::error::IterateError;
+use crate::services::entrypayload::EntryPayload;
+use crate::services::gatekeeper::{KeySlotInfo, replace_master_key, unlock_master_key};
+use crate::services::journalchain::verify_journal_chain;
+use crate::services::recordcipher::{
+    EncodingPolicy, RecordContext, RecordKind, journal_id, open_record, seal_record,
+};
+use rand::TryRngCore;
+use rusqlite::Connection;
+use serde::Serialize;
+use tracing::info;
+use zeroize::Zeroizing;
+
+const ROTATION_BATCH_SIZE: usize = 100;
+const PENDING_KEY_WRAPPING_AAD: &[u8] = b"pending-master-key-wrapping-v1";
+
+const KEY_EPOCH: &str = "key_epoch";
+const ROTATION_TARGET_EPOCH: &str = "rotation_target_epoch";
+const ROTATION_PENDING_KEY: &str = "rotation_pending_key";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RotationProgress {
+    pub processed: usize,
+    pub total: usize,
+}
+
+pub struct RotationOutcome {
+    pub service_keys: ServiceKeys,
+    /// The old recovery keys wrap the old master key, so a new one replaces them.
+    pub recovery_phrase: Zeroizing<String>,
+    /// Slots other than the one the rotation was run with, which were revoked.
+    pub revoked_slots: Vec<KeySlotInfo>,
+}
+
+/// Returns true if a rotation was started but did not finish, e.g. because the app crashed.
+pub fn is_rotation_pending(conn: &Connection) -> Result<bool, IterateError> {
+    Ok(MetadataRepository::new(conn)
+        .get(ROTATION_TARGET_EPOCH)?
+        .is_some())
+}
+
+/// Replaces the master key and re-encrypts every record under the new content key,
+/// and every topic and prompt under the new topic and blind index keys.
+///
+/// Records are re-encrypted in batches, each in its own transaction. The new master
+/// key is kept in `metadata`, wrapped by the old one, until the last batch is done,
+/// so calling this again with the same password resumes an interrupted rotation.
+/// Topics and prompts are re-encrypted in the transaction that moves the key epoch,
+/// so they are under the old keys for as long as the rotation is pending.
+/// The slot opened by `password` is re-wrapped; all other slots are revoked and
+/// replaced by a fresh recovery key, because their secrets are unknown here.
+///
+/// The record chain is checked before a rotation starts and restarted under the new
+/// chain key at the end, so a broken chain is never carried over as intact.
+pub fn rotate_master_key<R: TryRngCore>(
+    rng: &mut R,
+    conn: &mut Connection,
+    password: &[u8],
+    keyfile: Option<&[u8]>,
+    mut on_progress: impl FnMut(RotationProgress),
+) -> Result<RotationOutcome, IterateError> {
+    let unlocked = unlock_master_key(conn, password, keyfile)?;
+    let old_keys = derive_service_keys(&unlocked.master_key)?;
+    if !is_rotation_pending(conn)? && verify_journal_chain(conn, &old_keys)?.is_some() {
+        return Err(IterateError::JournalChainBroken);
+    }
+
+    let (target_epoch, new_master_key) = begin_or_resume(rng, conn, &unlocked.master_key)?;
+    let new_keys = derive_service_keys(&new_master_key)?;
+
+    let journal_id = journal_id(conn)?;
+    let encoding = EncodingPolicy::load(conn)?;
+    let total = RecordRepository::new(conn, &old_keys).count_before_epoch(target_epoch)?;
+    let mut processed = 0;
+    on_progress(RotationProgress { processed, total });
+
+    loop {
+        let tx = conn.transaction()?;
+        let old_records = RecordRepository::new(&tx, &old_keys);
+        let new_records = RecordRepository::new(&tx, &new_keys);
+
+        let batch = old_records.fetch_before_epoch(target_epoch, ROTATION_BATCH_SIZE)?;
+        if batch.is_empty() {
+            break;
+        }
+
+        for row in &batch {
+            let context = RecordContext {
+                journal_id,
+                record_id: row.id,
+                kind: RecordKind::of(row.is_summary_record),
+            };
+            let plaintext = Zeroizing::new(open_record(
+                &old_keys,
+                &context,
+                &row.encrypted_content,
+                row.wrapped_data_key.as_deref(),
+            )?);
+            // Entries that still hold raw text are upgraded to a payload while they are rewritten anyway.
+            let plaintext = match context.kind {
+                RecordKind::Entry => EntryPayload::from_bytes(&plaintext)?.to_bytes()?,
+                RecordKind::Summary => plaintext,
+            };
+            let sealed = seal_record(rng, &new_keys, &context, &plaintext, &encoding)?;
+            new_records.reencrypt(
+                rng,
+                row,
+                &sealed.encrypted_content,
+                &sealed.wrapped_data_key,
+                target_epoch,
+            )?;
+        }
+
+        tx.commit()?;
+        processed += batch.len();
+        on_progress(RotationProgress { processed, total });
+    }
+
+    let tx = conn.transaction()?;
+    TopicRepository::new(&tx, &old_keys).reencrypt_all(rng, &new_keys)?;
+    let replacement = replace_master_key(
+        rng,
+        &tx,
+        unlocked.slot_id,
+        password,
+        keyfile.filter(|_| unlocked.requires_keyfile),
+        &new_master_key,
+    )?;
+    let metadata = MetadataRepository::new(&tx);
+    metadata.set(KEY_EPOCH, &target_epoch.to_string())?;
+    metadata.delete(ROTATION_TARGET_EPOCH)?;
+    metadata.delete(ROTATION_PENDING_KEY)?;
+    RecordChain::new(&tx, &new_keys.chain).restart()?;
+    tx.commit()?;
+
+    info!("Key rotation to epoch {} finished", target_epoch);
+    Ok(RotationOutcome {
+        service_keys: new_keys,
+        recovery_phrase: replacement.recovery_phrase,
+        revoked_slots: replacement.revoked_slots,
+    })
+}
+
+/// Returns the target epoch and the new master key, creating both if no rotation is pending.
+fn begin_or_resume<R: TryRngCore>(
+    rng: &mut R,
+    conn: &mut Connection,
+    master_key: &Zeroizing<[u8; 32]>,
+) -> Result<(i64, Zeroizing<[u8; 32]>), IterateError> {
+    let tx = conn.transaction()?;
+    let metadata = MetadataRepository::new(&tx);
+
+    if let (Some(target_epoch), Some(pending_blob)) = (
+        metadata.get_i64(ROTATION_TARGET_EPOCH)?,
+        metadata.get_blob(ROTATION_PENDING_KEY)?,
+    ) {
+        let envelope = CryptoEnvelope::from_blob(&pending_blob)?;
+        let key_vec = Zeroizing::new(decrypt(master_key, &envelope, PENDING_KEY_WRAPPING_AAD)?);
+        let key: [u8; 32] = key_vec.as_slice().try_into().map_err(|_| {
+            IterateError::DecryptionFailed("Pending master key length mismatch".into())
+        })?;
+
+        info!("Resuming key rotation to epoch {}", target_epoch);
+        return Ok((target_epoch, Zeroizing::new(key)));
+    }
+
+    let target_epoch = metadata.get_i64(KEY_EPOCH)?.unwrap_or(0) + 1;
+    let mut new_master_key = Zeroizing::new([0u8; 32]);
+    rng.try_fill_bytes(new_master_key.as_mut())
+        .map_err(|_| IterateError::SystemRngFailure)?;
+
+    let envelope = encrypt(
+        rng,
+        master_key,
+        new_master_key.as_ref(),
+        PENDING_KEY_WRAPPING_AAD,
+    )?;
+    metadata.set_blob(ROTATION_PENDING_KEY, &envelope.to_blob()?)?;
+    metadata.set(ROTATION_TARGET_EPOCH, &target_epoch.to_string())?;
+    tx.commit()?;
+
+    info!("Key rotation to epoch {} started", target_epoch);
+    Ok((target_epoch, new_master_key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::open_database;
+    use crate::database::testing::TempJournal;
+    use crate::services::gatekeeper::initialize_key_store;
+    use rand::rngs::OsRng;
+
+    #[test]
+    fn rotates_topics_and_their_name_index() {
+        let journal = TempJournal::in_temp_dir();
+        let password = b"correct horse battery staple";
+        let mut conn = open_database(journal.0.clone()).unwrap();
+        let (old_keys, _) = initialize_key_store(&mut OsRng, &mut conn, password, None).unwrap();
+
+        let topics = TopicRepository::new(&conn, &old_keys);
+        let topic_id = topics
+            .insert_topic(&mut OsRng, "Work", Some("Meetings"))
+            .unwrap();
+        topics
+            .insert_prompt(&mut OsRng, &topic_id, "What went well?", true)
+            .unwrap();
+
+        let outcome = rotate_master_key(&mut OsRng, &mut conn, password, None, |_| {}).unwrap();
+
+        let topics = TopicRepository::new(&conn, &outcome.service_keys);
+        let topic = topics
+            .find_topic_by_name("work")
+            .unwrap()
+            .expect("the name index uses the new key");
+        assert_eq!(topic.name, "Work");
+        assert_eq!(topic.note.as_deref(), Some("Meetings"));
+        assert_eq!(
+            topics.list_prompts(&topic_id).unwrap()[0].text,
+            "What went well?"
+        );
+        assert!(matches!(
+            topics.insert_topic(&mut OsRng, " WORK ", None),
+            Err(IterateError::DuplicateTopic)
+        ));
+        assert!(
+            TopicRepository::new(&conn, &old_keys)
+                .list_topics()
+                .is_err()
+        );
+    }
+}
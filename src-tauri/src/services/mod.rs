@@ -1,2 +1,8 @@
 pub mod databasecleaner;
+pub mod entrypayload;
 pub mod gatekeeper;
+pub mod journalchain;
+pub mod journallock;
+pub mod keyrotation;
+pub mod privacymode;
+pub mod recordcipher;
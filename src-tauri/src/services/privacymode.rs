@@ -0,0 +1,168 @@
+use crate::crypto::servicekeys::ServiceKeys;
+use crate::database::records::checkpoint_wal;
+use crate::database::{RecordChain, RecordRepository};
+use crate::error::IterateError;
+use crate::services::journalchain::verify_journal_chain;
+use crate::services::recordcipher::{
+    EncodingPolicy, RecordContext, RecordKind, journal_id, open_record, seal_record,
+};
+use rand::TryRngCore;
+use rusqlite::Connection;
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+/// Turns privacy mode on or off and rewrites every record in the new form.
+///
+/// Turning it on also gives every record with a UUIDv7 id, which embeds its creation
+/// time, a random one, and restarts the record chain, whose ids and sequence numbers
+/// would otherwise keep the old order. The chain is checked first, so a broken one is
+/// never restarted as intact. Everything happens in one transaction.
+pub fn set_privacy_mode<R: TryRngCore>(
+    rng: &mut R,
+    conn: &Connection,
+    keys: &ServiceKeys,
+    enabled: bool,
+) -> Result<usize, IterateError> {
+    if enabled && verify_journal_chain(conn, keys)?.is_some() {
+        return Err(IterateError::JournalChainBroken);
+    }
+
+    let tx = conn.unchecked_transaction()?;
+    let records = RecordRepository::new(&tx, keys);
+    let rewritten = records.set_privacy_mode(rng, enabled)?;
+
+    if enabled {
+        let time_ordered = records.fetch_time_ordered()?;
+        if !time_ordered.is_empty() {
+            let journal_id = journal_id(&tx)?;
+            let encoding = EncodingPolicy::load(&tx)?;
+
+            for row in &time_ordered {
+                let kind = RecordKind::of(row.is_summary_record);
+                let plaintext = Zeroizing::new(open_record(
+                    keys,
+                    &RecordContext {
+                        journal_id,
+                        record_id: row.id,
+                        kind,
+                    },
+                    &row.encrypted_content,
+                    row.wrapped_data_key.as_deref(),
+                )?);
+
+                let new_id = Uuid::new_v4();
+                let context = RecordContext {
+                    journal_id,
+                    record_id: new_id,
+                    kind,
+                };
+                let sealed = seal_record(rng, keys, &context, &plaintext, &encoding)?;
+                records.rekey(
+                    rng,
+                    row,
+                    &new_id,
+                    &sealed.encrypted_content,
+                    &sealed.wrapped_data_key,
+                )?;
+            }
+            RecordChain::new(&tx, &keys.chain).restart()?;
+        }
+    }
+    tx.commit()?;
+
+    // The previous form of the rows must not linger in the WAL.
+    checkpoint_wal(conn)?;
+    Ok(rewritten)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::testing::{TempJournal, service_keys};
+    use crate::database::{TopicRepository, open_database};
+    use crate::services::entrypayload::EntryPayload;
+    use rand::rngs::OsRng;
+    use rusqlite::params;
+
+    fn ids(conn: &Connection, sql: &str) -> Vec<Uuid> {
+        conn.prepare(sql)
+            .unwrap()
+            .query_map([], |row| row.get::<_, Vec<u8>>(0))
+            .unwrap()
+            .map(|id| Uuid::from_slice(&id.unwrap()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn leaves_no_time_ordered_ids_behind() {
+        let journal = TempJournal::in_temp_dir();
+        let conn = open_database(journal.0.clone()).unwrap();
+        let keys = service_keys();
+        let records = RecordRepository::new(&conn, &keys);
+        records.authenticate_legacy_rows().unwrap();
+
+        let id = Uuid::now_v7();
+        let context = RecordContext {
+            journal_id: journal_id(&conn).unwrap(),
+            record_id: id,
+            kind: RecordKind::Entry,
+        };
+        let plaintext = EntryPayload::from_bytes(b"Dear diary")
+            .unwrap()
+            .to_bytes()
+            .unwrap();
+        let sealed = seal_record(
+            &mut OsRng,
+            &keys,
+            &context,
+            &plaintext,
+            &EncodingPolicy::load(&conn).unwrap(),
+        )
+        .unwrap();
+        records
+            .insert(
+                &mut OsRng,
+                &id,
+                &sealed.encrypted_content,
+                &sealed.wrapped_data_key,
+                None,
+            )
+            .unwrap();
+        let topic_id = TopicRepository::new(&conn, &keys)
+            .insert_topic(&mut OsRng, "Work", None)
+            .unwrap();
+        conn.execute(
+            "INSERT INTO record_topic (record_id, topic_id) VALUES (?, ?)",
+            params![id.as_bytes(), topic_id.as_bytes()],
+        )
+        .unwrap();
+
+        set_privacy_mode(&mut OsRng, &conn, &keys, true).unwrap();
+
+        let record_ids = ids(&conn, "SELECT record_id FROM record");
+        assert_eq!(record_ids.len(), 1);
+        let new_id = record_ids[0];
+        assert_eq!(new_id.get_version_num(), 4);
+        for sql in [
+            "SELECT record_id FROM record_chain",
+            "SELECT record_id FROM record_key",
+            "SELECT record_id FROM record_topic",
+        ] {
+            assert_eq!(ids(&conn, sql), [new_id], "{sql}");
+        }
+        assert!(verify_journal_chain(&conn, &keys).unwrap().is_none());
+
+        let row = records.get_record(new_id).unwrap();
+        let opened = open_record(
+            &keys,
+            &RecordContext {
+                record_id: new_id,
+                ..context
+            },
+            &row.encrypted_content,
+            row.wrapped_data_key.as_deref(),
+        )
+        .unwrap();
+        assert_eq!(opened, *plaintext);
+    }
+}
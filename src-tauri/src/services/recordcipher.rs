@@ -0,0 +1,187 @@
+use crate::crypto::aead::{decrypt, encrypt_encoded};
+use crate::crypto::compression::Compression;
+use crate::crypto::cryptoenvelope::CryptoEnvelope;
+use crate::crypto::datakey::DataKey;
+use crate::crypto::padding::Padding;
+use crate::crypto::servicekeys::ServiceKeys;
+use crate::database::MetadataRepository;
+use crate::error::IterateError;
+use rand::TryRngCore;
+use rusqlite::Connection;
+use serde::Serialize;
+use uuid::Uuid;
+
+const JOURNAL_ID: &str = "journal_id";
+const PADDING_POLICY: &str = "padding_policy";
+const COMPRESSION_POLICY: &str = "compression_policy";
+
+/// Envelopes older than this only bound the record id into their AAD.
+const RECORD_AAD_MIN_ENVELOPE_VERSION: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum RecordKind {
+    Entry,
+    Summary,
+}
+
+impl RecordKind {
+    pub fn of(is_summary_record: bool) -> Self {
+        if is_summary_record {
+            RecordKind::Summary
+        } else {
+            RecordKind::Entry
+        }
+    }
+}
+
+/// Versioned layout of the associated data of a record envelope.
+#[derive(Serialize)]
+enum RecordAad<'a> {
+    V1 {
+        journal_id: &'a [u8; 16],
+        record_id: &'a [u8; 16],
+        kind: RecordKind,
+    },
+}
+
+/// Everything a record's ciphertext is bound to, so it cannot be moved to
+/// another journal, another record, or from a summary to an entry.
+pub struct RecordContext {
+    pub journal_id: Uuid,
+    pub record_id: Uuid,
+    pub kind: RecordKind,
+}
+
+impl RecordContext {
+    /// Associated data for an envelope of `envelope_version`.
+    fn associated_data(&self, envelope_version: u8) -> Result<Vec<u8>, IterateError> {
+        if envelope_version < RECORD_AAD_MIN_ENVELOPE_VERSION {
+            return Ok(self.record_id.as_bytes().to_vec());
+        }
+
+        postcard::to_stdvec(&RecordAad::V1 {
+            journal_id: self.journal_id.as_bytes(),
+            record_id: self.record_id.as_bytes(),
+            kind: self.kind,
+        })
+        .map_err(|e| IterateError::PostCardSerializationFailed(e.to_string()))
+    }
+}
+
+/// The random identifier of this journal, created by the migrations.
+pub fn journal_id(conn: &Connection) -> Result<Uuid, IterateError> {
+    let value = MetadataRepository::new(conn)
+        .get(JOURNAL_ID)?
+        .ok_or_else(|| IterateError::Internal("Journal id is missing".into()))?;
+
+    Uuid::parse_str(&value).map_err(|e| IterateError::Internal(e.to_string()))
+}
+
+/// The padding applied to new record content. Journals without a setting use PADMÉ.
+pub fn padding_policy(conn: &Connection) -> Result<Padding, IterateError> {
+    match MetadataRepository::new(conn).get_i64(PADDING_POLICY)? {
+        Some(id) => Padding::from_id(id)
+            .ok_or_else(|| IterateError::Internal(format!("Unknown padding policy {}", id))),
+        None => Ok(Padding::Padme),
+    }
+}
+
+/// Changes the padding of records written from now on. Existing records keep theirs.
+pub fn set_padding_policy(conn: &Connection, padding: Padding) -> Result<(), IterateError> {
+    MetadataRepository::new(conn).set(PADDING_POLICY, &(padding as u8).to_string())
+}
+
+/// The compression applied to new record content. Journals without a setting use zstd.
+pub fn compression_policy(conn: &Connection) -> Result<Compression, IterateError> {
+    match MetadataRepository::new(conn).get_i64(COMPRESSION_POLICY)? {
+        Some(id) => Compression::from_id(id)
+            .ok_or_else(|| IterateError::Internal(format!("Unknown compression policy {}", id))),
+        None => Ok(Compression::Zstd),
+    }
+}
+
+/// Changes the compression of records written from now on. Existing records keep theirs.
+pub fn set_compression_policy(
+    conn: &Connection,
+    compression: Compression,
+) -> Result<(), IterateError> {
+    MetadataRepository::new(conn).set(COMPRESSION_POLICY, &(compression as u8).to_string())
+}
+
+/// How new record content is encoded before encryption.
+#[derive(Debug, Clone, Copy)]
+pub struct EncodingPolicy {
+    pub compression: Compression,
+    pub padding: Padding,
+}
+
+impl EncodingPolicy {
+    pub fn load(conn: &Connection) -> Result<Self, IterateError> {
+        Ok(Self {
+            compression: compression_policy(conn)?,
+            padding: padding_policy(conn)?,
+        })
+    }
+}
+
+/// Serialized blobs of an encrypted record, ready for the `record` table.
+pub struct SealedRecord {
+    pub encrypted_content: Vec<u8>,
+    pub wrapped_data_key: Vec<u8>,
+}
+
+/// Compresses, pads and encrypts the plaintext under a fresh data key and wraps
+/// that key with the content key.
+pub fn seal_record<R: TryRngCore>(
+    rng: &mut R,
+    keys: &ServiceKeys,
+    context: &RecordContext,
+    plaintext: &[u8],
+    encoding: &EncodingPolicy,
+) -> Result<SealedRecord, IterateError> {
+    let data_key = DataKey::generate(rng)?;
+    let content = encrypt_encoded(
+        rng,
+        data_key.key(),
+        plaintext,
+        &context.associated_data(CryptoEnvelope::ENCODED_VERSION)?,
+        encoding.compression,
+        encoding.padding,
+    )?;
+    let wrapped_data_key = data_key.wrap(
+        rng,
+        &keys.content,
+        &context.associated_data(CryptoEnvelope::CURRENT_VERSION)?,
+    )?;
+
+    Ok(SealedRecord {
+        encrypted_content: content.to_blob()?,
+        wrapped_data_key: wrapped_data_key.to_blob()?,
+    })
+}
+
+/// Decrypts a record. Records without a data key predate per-record keys
+/// and were encrypted with the content key directly.
+/// V1 envelopes are opened with the record id as their only AAD.
+pub fn open_record(
+    keys: &ServiceKeys,
+    context: &RecordContext,
+    encrypted_content: &[u8],
+    wrapped_data_key: Option<&[u8]>,
+) -> Result<Vec<u8>, IterateError> {
+    let content = CryptoEnvelope::from_blob(encrypted_content)?;
+    let content_aad = context.associated_data(content.version())?;
+
+    match wrapped_data_key {
+        Some(blob) => {
+            let envelope = CryptoEnvelope::from_blob(blob)?;
+            let data_key = DataKey::unwrap(
+                &keys.content,
+                &envelope,
+                &context.associated_data(envelope.version())?,
+            )?;
+            decrypt(data_key.key(), &content, &content_aad)
+        }
+        None => decrypt(&keys.content, &content, &content_aad),
+    }
+}
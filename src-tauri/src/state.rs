@@ -1,24 +1,386 @@
-use parking_lot::Mutex;
-use std::path::PathBuf;
+use parking_lot::{Mutex, MutexGuard};
+use rusqlite::Connection;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tracing::warn;
 
 use crate::crypto::servicekeys::ServiceKeys;
+use crate::error::IterateError;
+use crate::services::journallock::JournalLock;
 
 #[derive(serde::Serialize, serde::Deserialize, Clone)]
 pub struct AppConfig {
     /// Number of days to keep a record after soft-deletion
     pub soft_delete_retention_days: u64,
+    /// Walk the record chain every time a journal is opened and unlocked
+    pub verify_chain_on_open: bool,
+    /// Minutes without activity after which an unlocked journal is locked, 0 disables it
+    pub idle_lock_minutes: u64,
 }
 
 impl AppConfig {
     pub fn default() -> Self {
         AppConfig {
             soft_delete_retention_days: 30,
+            verify_chain_on_open: true,
+            idle_lock_minutes: 15,
         }
     }
 }
 
+/// The journal the app is working on. Keys and connection only exist inside
+/// `Unlocked`, so they cannot outlive or belong to another journal than `path`.
+pub enum Session {
+    NoJournal,
+    Opened {
+        path: PathBuf,
+    },
+    /// The keys are boxed so that moving the session does not copy them around.
+    Unlocked {
+        path: PathBuf,
+        keys: Box<ServiceKeys>,
+        conn: Connection,
+    },
+    /// The keys and connection of an unlocked session are being torn down.
+    Locking {
+        path: PathBuf,
+    },
+}
+
+impl Session {
+    fn name(&self) -> &'static str {
+        match self {
+            Session::NoJournal => "no journal",
+            Session::Opened { .. } => "opened",
+            Session::Unlocked { .. } => "unlocked",
+            Session::Locking { .. } => "locking",
+        }
+    }
+
+    /// The selected journal file, unless it is being locked.
+    pub fn path(&self) -> Option<&PathBuf> {
+        match self {
+            Session::Opened { path } | Session::Unlocked { path, .. } => Some(path),
+            Session::NoJournal | Session::Locking { .. } => None,
+        }
+    }
+
+    pub fn unlocked(&self) -> Option<(&Connection, &ServiceKeys)> {
+        match self {
+            Session::Unlocked { keys, conn, .. } => Some((conn, keys)),
+            _ => None,
+        }
+    }
+
+    pub fn unlocked_mut(&mut self) -> Option<(&mut Connection, &mut ServiceKeys)> {
+        match self {
+            Session::Unlocked { keys, conn, .. } => Some((conn, keys)),
+            _ => None,
+        }
+    }
+
+    pub fn is_unlocked(&self) -> bool {
+        matches!(self, Session::Unlocked { .. })
+    }
+}
+
 pub struct AppState {
     pub app_config: Mutex<AppConfig>,
-    pub db_path: Mutex<Option<PathBuf>>,
-    pub serivce_keys: Mutex<Option<ServiceKeys>>,
-}
\ No newline at end of file
+    pub session: Mutex<Session>,
+    /// Held from opening a journal until another one is opened, so that no other instance
+    /// writes to it between the unlocks. Kept apart from the session, so that it can be
+    /// refreshed while a long command holds the session.
+    pub journal_lock: Mutex<Option<JournalLock>>,
+    /// Wall-clock time, so that time spent suspended counts as idle
+    pub last_activity: Mutex<SystemTime>,
+}
+
+impl AppState {
+    /// Selects a journal file, locked with `lock`. An unlocked session is dropped with
+    /// its keys, whichever journal it belonged to.
+    pub fn open_journal(&self, path: PathBuf, lock: JournalLock) -> Result<(), IterateError> {
+        let mut session = self.session.lock();
+        if let Session::Locking { .. } = *session {
+            return Err(IterateError::InvalidSessionTransition {
+                from: session.name(),
+                to: "opened",
+            });
+        }
+
+        *session = Session::Opened { path };
+        *self.journal_lock.lock() = Some(lock);
+        Ok(())
+    }
+
+    /// Locks the journal and deselects it, which releases its journal lock.
+    pub fn close_journal(&self) {
+        self.lock_journal();
+
+        let mut session = self.session.lock();
+        if let Session::Opened { .. } = *session {
+            *session = Session::NoJournal;
+            *self.journal_lock.lock() = None;
+        }
+    }
+
+    /// Makes sure this instance still holds the lock of the selected journal, taking it
+    /// again if it was lost, e.g. while this machine was suspended. Takes the locked
+    /// session, so that the selected journal cannot change meanwhile.
+    pub fn hold_journal_lock(&self, session: &Session) -> Result<(), IterateError> {
+        let path = session
+            .path()
+            .ok_or(IterateError::InvalidSessionTransition {
+                from: session.name(),
+                to: "opened",
+            })?;
+
+        let mut journal_lock = self.journal_lock.lock();
+        let held = match journal_lock.as_mut() {
+            Some(lock) => lock.refresh()?,
+            None => false,
+        };
+        if held {
+            return Ok(());
+        }
+        // The lost lock is released before the file is locked again.
+        *journal_lock = None;
+        *journal_lock = Some(JournalLock::acquire(path)?);
+        Ok(())
+    }
+
+    /// Hands keys and connection to the session. Fails unless `path` is still the
+    /// opened journal, e.g. because another one was opened during the unlock.
+    pub fn unlock_journal(
+        &self,
+        path: &Path,
+        keys: ServiceKeys,
+        conn: Connection,
+    ) -> Result<(), IterateError> {
+        let mut session = self.session.lock();
+        match &*session {
+            Session::Opened { path: opened } if opened == path => {
+                *session = Session::Unlocked {
+                    path: opened.clone(),
+                    keys: Box::new(keys),
+                    conn,
+                };
+                Ok(())
+            }
+            other => Err(IterateError::InvalidSessionTransition {
+                from: other.name(),
+                to: "unlocked",
+            }),
+        }
+    }
+
+    /// Drops the service keys, which zeroizes them, and closes the connection. The journal
+    /// stays opened, and locked against other instances. Returns false if it was not unlocked.
+    /// Waits for a command that holds the session, e.g. a key rotation, to finish.
+    pub fn lock_journal(&self) -> bool {
+        self.lock_session(self.session.lock())
+    }
+
+    /// Like `lock_journal`, but returns `None` instead of waiting while a command holds the session.
+    pub fn try_lock_journal(&self) -> Option<bool> {
+        self.session
+            .try_lock()
+            .map(|session| self.lock_session(session))
+    }
+
+    fn lock_session(&self, mut session: MutexGuard<'_, Session>) -> bool {
+        let (path, keys, conn) = match std::mem::replace(&mut *session, Session::NoJournal) {
+            Session::Unlocked { path, keys, conn } => {
+                *session = Session::Locking { path: path.clone() };
+                (path, keys, conn)
+            }
+            other => {
+                *session = other;
+                return false;
+            }
+        };
+        drop(session);
+
+        drop(keys);
+        if let Err((_, e)) = conn.close() {
+            warn!("Closing the journal failed: {}", e);
+        }
+
+        *self.session.lock() = Session::Opened { path };
+        true
+    }
+
+    /// Refreshes the lock of the selected journal. Returns false if another instance
+    /// took it over.
+    pub fn refresh_journal_lock(&self) -> bool {
+        let mut journal_lock = self.journal_lock.lock();
+        let Some(lock) = journal_lock.as_mut() else {
+            return true;
+        };
+
+        lock.refresh().unwrap_or_else(|e| {
+            warn!("Refreshing the journal lock failed: {}", e);
+            true
+        })
+    }
+
+    pub fn record_activity(&self) {
+        *self.last_activity.lock() = SystemTime::now();
+    }
+
+    /// True once no activity was recorded for longer than the idle timeout.
+    pub fn is_idle(&self) -> bool {
+        let minutes = self.app_config.lock().idle_lock_minutes;
+        if minutes == 0 {
+            return false;
+        }
+
+        let idle = self.last_activity.lock().elapsed().unwrap_or_default();
+        idle >= Duration::from_secs(minutes * 60)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::testing::{TempJournal, service_keys};
+
+    fn app_state() -> AppState {
+        AppState {
+            app_config: Mutex::new(AppConfig::default()),
+            session: Mutex::new(Session::NoJournal),
+            journal_lock: Mutex::new(None),
+            last_activity: Mutex::new(SystemTime::now()),
+        }
+    }
+
+    fn open(state: &AppState, journal: &TempJournal) -> Result<(), IterateError> {
+        let lock = JournalLock::acquire(&journal.0)?;
+        state.open_journal(journal.0.clone(), lock)
+    }
+
+    fn unlock(state: &AppState, journal: &TempJournal) -> Result<(), IterateError> {
+        let conn = Connection::open_in_memory().unwrap();
+        state.unlock_journal(&journal.0, service_keys(), conn)
+    }
+
+    #[test]
+    fn unlocks_only_the_opened_journal() {
+        let state = app_state();
+        let journal = TempJournal::in_temp_dir();
+        let other = TempJournal::in_temp_dir();
+
+        assert!(matches!(
+            unlock(&state, &journal),
+            Err(IterateError::InvalidSessionTransition {
+                from: "no journal",
+                ..
+            })
+        ));
+
+        open(&state, &other).unwrap();
+        assert!(matches!(
+            unlock(&state, &journal),
+            Err(IterateError::InvalidSessionTransition { from: "opened", .. })
+        ));
+
+        open(&state, &journal).unwrap();
+        unlock(&state, &journal).expect("the opened journal unlocks");
+        assert!(state.session.lock().is_unlocked());
+        assert!(matches!(
+            unlock(&state, &journal),
+            Err(IterateError::InvalidSessionTransition {
+                from: "unlocked",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn keeps_the_journal_locked_between_unlocks() {
+        let state = app_state();
+        let journal = TempJournal::in_temp_dir();
+
+        assert!(!state.lock_journal());
+
+        open(&state, &journal).unwrap();
+        assert!(matches!(
+            JournalLock::acquire(&journal.0),
+            Err(IterateError::JournalInUse(_))
+        ));
+        unlock(&state, &journal).unwrap();
+        assert!(state.lock_journal());
+
+        assert!(matches!(&*state.session.lock(), Session::Opened { path } if *path == journal.0));
+        assert!(matches!(
+            JournalLock::acquire(&journal.0),
+            Err(IterateError::JournalInUse(_))
+        ));
+        assert!(!state.lock_journal());
+
+        state.hold_journal_lock(&state.session.lock()).unwrap();
+        unlock(&state, &journal).expect("the journal unlocks again");
+    }
+
+    #[test]
+    fn reports_a_busy_session_instead_of_waiting() {
+        let state = app_state();
+        let journal = TempJournal::in_temp_dir();
+        open(&state, &journal).unwrap();
+        unlock(&state, &journal).unwrap();
+
+        let session = state.session.lock();
+        assert_eq!(state.try_lock_journal(), None);
+        drop(session);
+
+        assert_eq!(state.try_lock_journal(), Some(true));
+        assert_eq!(state.try_lock_journal(), Some(false));
+    }
+
+    #[test]
+    fn closing_releases_the_journal_lock() {
+        let state = app_state();
+        let journal = TempJournal::in_temp_dir();
+
+        open(&state, &journal).unwrap();
+        unlock(&state, &journal).unwrap();
+        state.close_journal();
+
+        assert!(state.session.lock().path().is_none());
+        assert!(state.journal_lock.lock().is_none());
+        JournalLock::acquire(&journal.0).expect("the journal is free again");
+    }
+
+    #[test]
+    fn opening_another_journal_drops_the_unlocked_one() {
+        let state = app_state();
+        let journal = TempJournal::in_temp_dir();
+        let other = TempJournal::in_temp_dir();
+
+        open(&state, &journal).unwrap();
+        unlock(&state, &journal).unwrap();
+        open(&state, &other).unwrap();
+
+        let session = state.session.lock();
+        assert!(session.unlocked().is_none());
+        assert_eq!(session.path(), Some(&other.0));
+        JournalLock::acquire(&journal.0).expect("the first journal is released");
+    }
+
+    #[test]
+    fn refuses_to_open_a_journal_while_locking() {
+        let state = app_state();
+        let journal = TempJournal::in_temp_dir();
+        *state.session.lock() = Session::Locking {
+            path: journal.0.clone(),
+        };
+
+        assert!(matches!(
+            open(&state, &journal),
+            Err(IterateError::InvalidSessionTransition {
+                from: "locking",
+                to: "opened"
+            })
+        ));
+        assert!(state.session.lock().path().is_none());
+    }
+}